@@ -0,0 +1,49 @@
+#!/usr/bin/env bash
+# Times cbc/loop.cbc with a release build of a revision and of the working tree,
+# in both encodings, and prints the best and the median of the runs.
+#
+#     ./bench.sh [revision] [runs]
+#
+# The revision defaults to HEAD, so the numbers show what uncommitted changes do.
+# ./bench.sh 'HEAD^{/Pre-decode programs}~' compares with the CVM before programs were decoded up front.
+set -euo pipefail
+
+revision=${1:-HEAD}
+runs=${2:-5}
+root=$(cd "$(dirname "$0")" && pwd)
+before=$(mktemp -d)
+trap 'git -C "$root" worktree remove --force "$before" >/dev/null 2>&1 || rm -rf "$before"' EXIT
+
+git -C "$root" worktree add --detach -q "$before" "$revision"
+echo "building $revision and the working tree"
+(cd "$before" && cargo build -q --release)
+(cd "$root" && cargo build -q --release)
+
+# best and median of runs timings of carpet on loop.cbc, in seconds
+time_runs() {
+    local carpet=$1
+    shift
+    local times=()
+    for _ in $(seq "$runs"); do
+        local start end
+        start=$(date +%s%N)
+        "$carpet" "$@" "$root/cbc/loop.cbc" >/dev/null 2>&1
+        end=$(date +%s%N)
+        times+=($(( (end - start) / 1000000 )))
+    done
+    local sorted
+    sorted=($(printf '%s\n' "${times[@]}" | sort -n))
+    printf 'best %d.%02ds  median %d.%02ds' \
+        $(( sorted[0] / 1000 )) $(( sorted[0] % 1000 / 10 )) \
+        $(( sorted[runs / 2] / 1000 )) $(( sorted[runs / 2] % 1000 / 10 ))
+}
+
+for encoding in padded compact; do
+    flags=()
+    if [ "$encoding" = compact ]; then
+        flags=(--compact)
+    fi
+    echo "$encoding, $runs runs"
+    echo "  before  $(time_runs "$before/target/release/carpet" "${flags[@]+"${flags[@]}"}")"
+    echo "  after   $(time_runs "$root/target/release/carpet" "${flags[@]+"${flags[@]}"}")"
+done
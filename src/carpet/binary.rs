@@ -0,0 +1,66 @@
+use std::error::Error;
+
+//Helpers for the binary files carpet writes. Integers are little endian and
+//byte strings are prefixed with their length as a u32.
+
+pub fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend(&value.to_le_bytes());
+}
+
+pub fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len() as u32);
+    out.extend(bytes);
+}
+
+pub fn write_str(out: &mut Vec<u8>, string: &str) {
+    write_bytes(out, string.as_bytes());
+}
+
+//memory is written without the zeros it ends with, and filled up with them when read
+pub fn trim_zeros(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().rposition(|&byte| byte != 0).map_or(0, |last| last + 1);
+    &bytes[..len]
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    //fails unless the bytes start with the magic number of the expected kind of file
+    pub fn new(bytes: &'a [u8], magic: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if !bytes.starts_with(magic) {
+            return Err(format!("not a {} file", String::from_utf8_lossy(magic)).into());
+        }
+        Ok(Self { bytes, position: magic.len() })
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let bytes = self.bytes.get(self.position..self.position + len).ok_or("file is truncated")?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn string(&mut self) -> Result<String, Box<dyn Error>> {
+        Ok(String::from_utf8(self.bytes()?)?)
+    }
+}
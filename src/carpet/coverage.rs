@@ -0,0 +1,186 @@
+use crate::carpet::cvm::REGISTER_COUNT;
+use crate::carpet::debug_info::DebugInfo;
+use crate::carpet::instructions::CI;
+use crate::carpet::program::Program;
+use crate::carpet::tracer::Tracer;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+//how often an instruction ran, and for jeq and jne how often they jumped and fell through
+#[derive(Debug, Clone, Copy, Default)]
+struct Hits {
+    count: u64,
+    taken: u64,
+    not_taken: u64,
+}
+
+//a source line with every instruction assembled from it, in lcov terms
+#[derive(Debug, Default)]
+struct LineCoverage {
+    count: u64,
+    //taken and not taken counts of every branch on the line, in order
+    branches: Vec<(u64, u64)>,
+}
+
+//Records which instructions of a program ran and which way every conditional
+//jump went, and reports it per source line.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    debug_info: DebugInfo,
+    instructions: Vec<(usize, CI)>,
+    //indexed by pc
+    hits: Vec<Hits>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //instructions that ran out of all of them, and the same for both ways of every branch
+    pub fn summary(&self) -> String {
+        let ran = self.instructions.iter().filter(|(pc, _)| self.hits[*pc].count > 0).count();
+        let mut branches = 0;
+        let mut covered = 0;
+        for (pc, instruction) in &self.instructions {
+            if matches!(instruction, CI::JEQ(..) | CI::JNE(..)) {
+                branches += 2;
+                covered += (self.hits[*pc].taken > 0) as usize + (self.hits[*pc].not_taken > 0) as usize;
+            }
+        }
+        format!(
+            "coverage: {}/{} instructions, {}/{} branches",
+            ran,
+            self.instructions.len(),
+            covered,
+            branches
+        )
+    }
+
+    //one lcov record per source file, instructions without debug info are left out
+    pub fn lcov(&self) -> String {
+        let mut files: BTreeMap<&str, BTreeMap<usize, LineCoverage>> = BTreeMap::new();
+        for (pc, instruction) in &self.instructions {
+            let location = match self.debug_info.lookup(*pc) {
+                Some(location) => location,
+                None => continue,
+            };
+            let hits = self.hits[*pc];
+            let line = files.entry(location.path).or_default().entry(location.entry.line).or_default();
+            line.count = line.count.max(hits.count);
+            if matches!(instruction, CI::JEQ(..) | CI::JNE(..)) {
+                line.branches.push((hits.taken, hits.not_taken));
+            }
+        }
+        let mut lcov = String::new();
+        for (path, lines) in files {
+            let _ = writeln!(lcov, "TN:");
+            let _ = writeln!(lcov, "SF:{}", path);
+            let (mut branches, mut branches_hit) = (0, 0);
+            for (number, line) in &lines {
+                for (block, (taken, not_taken)) in line.branches.iter().enumerate() {
+                    for (branch, count) in [taken, not_taken].iter().enumerate() {
+                        //lcov writes - for a branch whose line never ran
+                        let count = if line.count == 0 { "-".to_string() } else { count.to_string() };
+                        let _ = writeln!(lcov, "BRDA:{},{},{},{}", number, block, branch, count);
+                    }
+                    branches += 2;
+                    branches_hit += (*taken > 0) as usize + (*not_taken > 0) as usize;
+                }
+            }
+            for (number, line) in &lines {
+                let _ = writeln!(lcov, "DA:{},{}", number, line.count);
+            }
+            let _ = writeln!(lcov, "BRF:{}", branches);
+            let _ = writeln!(lcov, "BRH:{}", branches_hit);
+            let _ = writeln!(lcov, "LF:{}", lines.len());
+            let _ = writeln!(lcov, "LH:{}", lines.values().filter(|line| line.count > 0).count());
+            let _ = writeln!(lcov, "end_of_record");
+        }
+        lcov
+    }
+}
+
+impl Tracer for Coverage {
+    fn loaded(&mut self, program: &Program) {
+        *self = Coverage {
+            debug_info: program.debug_info.clone(),
+            instructions: program.instructions().0,
+            hits: vec![Hits::default(); program.code.len()],
+        };
+    }
+
+    fn before(&mut self, pc: usize, instruction: &CI, registers: &[u32; REGISTER_COUNT]) {
+        let hits = &mut self.hits[pc];
+        hits.count += 1;
+        //jeq jumps when its first register isn't zero, jne when it is
+        let taken = match instruction {
+            CI::JEQ(check, _) => registers[*check as usize] != 0,
+            CI::JNE(check, _) => registers[*check as usize] == 0,
+            _ => return,
+        };
+        if taken {
+            hits.taken += 1;
+        } else {
+            hits.not_taken += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet::cvm::CVM;
+    use crate::carpet_assembler::assembler::CarpetAssembler;
+    use crate::parser::parse::Parser;
+
+    //the loop branch goes both ways, the beq on line 4 only jumps and lines 5 and 6 never run
+    const COVERED: &str = "\
+        loadi r1 2\n\
+        loop: dec r1\n\
+        bne r1 r0 loop\n\
+        beq r1 r0 done\n\
+        inc r2\n\
+        beq r2 r0 done\n\
+        done: hlt\n";
+
+    fn coverage() -> Coverage {
+        let module = Parser::new().parse_source("covered.cbc", COVERED).unwrap();
+        let mut cvm = CVM::with_tracer(Coverage::new());
+        cvm.new_program(CarpetAssembler::new().assemble(module).unwrap());
+        cvm.run().unwrap();
+        std::mem::take(cvm.tracer_mut())
+    }
+
+    #[test]
+    fn summary() {
+        assert_eq!(coverage().summary(), "coverage: 9/13 instructions, 3/6 branches");
+    }
+
+    #[test]
+    fn lcov() {
+        assert_eq!(
+            coverage().lcov(),
+            "TN:\n\
+             SF:covered.cbc\n\
+             BRDA:3,0,0,1\n\
+             BRDA:3,0,1,1\n\
+             BRDA:4,0,0,1\n\
+             BRDA:4,0,1,0\n\
+             BRDA:6,0,0,-\n\
+             BRDA:6,0,1,-\n\
+             DA:1,1\n\
+             DA:2,2\n\
+             DA:3,2\n\
+             DA:4,1\n\
+             DA:5,0\n\
+             DA:6,0\n\
+             DA:7,1\n\
+             BRF:6\n\
+             BRH:3\n\
+             LF:7\n\
+             LH:5\n\
+             end_of_record\n"
+        );
+    }
+}
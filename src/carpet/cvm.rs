@@ -1,391 +1,731 @@
-use crate::carpet::instructions::Opcode;
+use crate::carpet::instructions::{Encoding, Register, CI};
 
 use crate::carpet::cvm_heap::CVMHeap;
-use std::mem::transmute;
+use crate::carpet::debug_info::DebugInfo;
+use crate::carpet::program::Program;
+use crate::carpet::snapshot::Snapshot;
+use crate::carpet::tracer::{Change, NoTracer, Tracer};
+use crate::carpet::undo::UndoLog;
+use crate::carpet::memory::{self, Region, HEAP_BASE, STACK_BASE, STACK_BYTES, WORD_BYTES};
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::mem;
 
 
 pub const REGISTER_COUNT: usize = 32;
 pub const STACK_SIZE: usize = 256;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trap {
+    NullPointer(usize),
+    Unmapped(usize),
+    StackOverflow,
+    StackUnderflow,
+    OutOfHeap(usize),
+    InvalidFree(usize),
+    InvalidInstruction(usize),
+    InvalidJump(usize),
+    DivideByZero,
+    OutOfFuel,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::NullPointer(address) => write!(f, "null pointer access at {:#x}", address),
+            Trap::Unmapped(address) => write!(f, "access to unmapped address {:#x}", address),
+            Trap::StackOverflow => write!(f, "stack overflow"),
+            Trap::StackUnderflow => write!(f, "stack underflow"),
+            Trap::OutOfHeap(size) => write!(f, "out of heap allocating {} bytes", size),
+            Trap::InvalidFree(address) => write!(f, "free of unallocated pointer {:#x}", address),
+            Trap::InvalidInstruction(offset) => write!(f, "invalid instruction at {:#x}", offset),
+            Trap::InvalidJump(offset) => write!(f, "jump to {:#x}, where no instruction starts", offset),
+            Trap::DivideByZero => write!(f, "division by zero"),
+            Trap::OutOfFuel => write!(f, "ran out of fuel"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CVMError {
+    pub pc: usize,
+    pub trap: Trap,
+    //the source line of the instruction at pc, when the program has debug info
+    pub source: Option<String>,
+}
+
+impl fmt::Display for CVMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{}: CVM {}", source, self.trap),
+            None => write!(f, "CVM {} (pc={})", self.trap, self.pc),
+        }
+    }
+}
+
+impl Error for CVMError {}
+
+//an offset that no instruction starts at
+const NO_INSTRUCTION: u32 = u32::MAX;
+
 #[derive(Debug)]
-pub struct CVM {
+pub struct CVM<T: Tracer = NoTracer> {
     registers: [u32; REGISTER_COUNT],
-    counter: usize,
-    program: Vec<u8>,
+    //index into instructions of the next instruction to run
+    index: usize,
+    //The program is decoded once when it is loaded. None stands for bytes that
+    //aren't a valid instruction, decoding stops there since nothing tells where
+    //the next instruction would start.
+    instructions: Vec<Option<CI>>,
+    //the byte offset of every instruction, followed by the length of the code
+    offsets: Vec<usize>,
+    //the index of the instruction starting at every byte offset, jumps go through it
+    index_of: Vec<u32>,
+    data: Vec<u8>,
+    debug_info: DebugInfo,
+    //the code as it was loaded, for snapshots
+    code: Vec<u8>,
+    encoding: Encoding,
 
-    stack: [u32; STACK_SIZE],
+    stack: [u8; STACK_BYTES],
     stack_pointer: usize,
+    //the number of stack slots a program may use, at most STACK_SIZE
+    stack_limit: usize,
 
     heap: CVMHeap,
+
+    //instructions a program may still run, and how many every program starts with
+    fuel: u64,
+    fuel_limit: u64,
+
+    //0 once the program stopped at hlt, the code it gave exit, None while it runs
+    //and when it ran past its last instruction
+    exit_code: Option<i32>,
+
+    //what print and prints wrote, when it's kept instead of going to stdout
+    output: Option<Vec<u8>>,
+
+    tracer: T,
 }
 
 
 impl CVM {
     pub fn new() -> Self {
+        CVM::with_tracer(NoTracer)
+    }
+}
+
+impl CVM<UndoLog> {
+    //takes back the last instruction that ran, false when the log doesn't go back any further
+    pub fn step_back(&mut self) -> bool {
+        match self.tracer.pop() {
+            Some((pc, changes)) => {
+                self.undo(pc, &changes);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<T: Tracer> CVM<T> {
+    pub fn with_tracer(tracer: T) -> Self {
         Self {
             registers: [0u32; REGISTER_COUNT],
-            counter: 0,
-            program: vec![],
-            stack: [0u32; STACK_SIZE],
+            index: 0,
+            instructions: vec![],
+            offsets: vec![0],
+            index_of: vec![0],
+            data: vec![],
+            debug_info: DebugInfo::default(),
+            code: vec![],
+            encoding: Encoding::default(),
+            stack: [0u8; STACK_BYTES],
             stack_pointer: 0,
+            stack_limit: STACK_SIZE,
             heap: CVMHeap::new(),
+            fuel: u64::MAX,
+            fuel_limit: u64::MAX,
+            exit_code: None,
+            output: None,
+            tracer,
         }
     }
 
-    pub fn read_next_raw(&mut self) -> u32 {
-        let index = self.next_8_bits() as usize;
-        self.registers[index]
+    //the stack holds at most slots values, more than STACK_SIZE can't be mapped
+    pub fn with_stack_size(mut self, slots: usize) -> Self {
+        self.stack_limit = slots.min(STACK_SIZE);
+        self
+    }
+
+    //the heap holds at most bytes bytes, more than HEAP_SIZE can't be mapped
+    pub fn with_heap_size(mut self, bytes: usize) -> Self {
+        self.heap = CVMHeap::with_size(bytes);
+        self
+    }
+
+    //every program stops with a trap once it has run fuel instructions
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel_limit = fuel;
+        self.fuel = fuel;
+        self
+    }
+
+    //print and prints write to a buffer that take_output empties, instead of to stdout
+    pub fn with_captured_output(mut self) -> Self {
+        self.output = Some(vec![]);
+        self
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    pub fn tracer_mut(&mut self) -> &mut T {
+        &mut self.tracer
     }
 
-    pub fn read_next_i32(&mut self) -> i32 {
-        let index = self.next_8_bits() as usize;
-        unsafe { transmute::<u32, i32>(self.registers[index]) }
+    fn raw(&self, register: Register) -> u32 {
+        self.registers[register as usize]
     }
 
-    pub fn read_next_f32(&mut self) -> f32 {
-        let index = self.next_8_bits() as usize;
-        unsafe { transmute::<u32, f32>(self.registers[index]) }
+    fn int(&self, register: Register) -> i32 {
+        self.registers[register as usize] as i32
     }
 
-    pub fn write_next_raw(&mut self, value: u32) {
-        let index = self.next_8_bits() as usize;
-        self.registers[index] = value;
+    fn float(&self, register: Register) -> f32 {
+        f32::from_bits(self.registers[register as usize])
     }
 
-    pub fn write_next_i32(&mut self, value: i32) {
-        let index = self.next_8_bits() as usize;
-        self.registers[index] = unsafe { transmute::<i32, u32>(value) };
+    fn set_raw(&mut self, register: Register, value: u32) {
+        self.registers[register as usize] = value;
     }
 
-    pub fn write_next_f32(&mut self, value: f32) {
-        let index = self.next_8_bits() as usize;
-        self.registers[index] = unsafe { transmute::<f32, u32>(value) };
+    fn set_int(&mut self, register: Register, value: i32) {
+        self.registers[register as usize] = value as u32;
     }
 
-    pub fn new_program(&mut self, program: Vec<u8>) {
+    fn set_float(&mut self, register: Register, value: f32) {
+        self.registers[register as usize] = value.to_bits();
+    }
+
+    fn set_stack_pointer(&mut self, stack_pointer: usize) {
+        self.tracer.changed(Change::StackPointer(self.stack_pointer));
+        self.stack_pointer = stack_pointer;
+    }
+
+    //every program starts with empty registers, stack and heap
+    pub fn new_program(&mut self, program: Program) {
+        self.tracer.loaded(&program);
         self.registers = [0u32; REGISTER_COUNT];
-        self.program = program;
-        self.counter = 0;
+        self.stack = [0u8; STACK_BYTES];
+        self.stack_pointer = 0;
+        self.heap.reset();
+        self.data = vec![];
+        self.decode_program(program);
+        self.index = 0;
+        self.fuel = self.fuel_limit;
+        self.exit_code = None;
+    }
+
+    //Replaces the program with one whose code starts with the code of the current one
+    //and runs on from where that ended. Registers, the stack, the heap and the static
+    //data the current program had are kept as they are.
+    pub fn extend_program(&mut self, program: Program) {
+        let end = self.offsets[self.offsets.len() - 1];
+        self.decode_program(program);
+        self.exit_code = None;
+        self.index = match self.index_of.get(end) {
+            Some(&index) if index != NO_INSTRUCTION => index as usize,
+            _ => self.instructions.len(),
+        };
+    }
+
+    fn decode_program(&mut self, program: Program) {
+        self.instructions.clear();
+        self.offsets.clear();
+        self.index_of = vec![NO_INSTRUCTION; program.code.len() + 1];
+        let (instructions, invalid) = program.instructions();
+        let decoded = instructions.into_iter().map(|(offset, instruction)| (offset, Some(instruction)));
+        for (offset, instruction) in decoded.chain(invalid.map(|offset| (offset, None))) {
+            self.index_of[offset] = self.instructions.len() as u32;
+            self.offsets.push(offset);
+            self.instructions.push(instruction);
+        }
+        self.index_of[program.code.len()] = self.instructions.len() as u32;
+        self.offsets.push(program.code.len());
+        let mut data = program.data;
+        let kept = self.data.len().min(data.len());
+        data[..kept].copy_from_slice(&self.data[..kept]);
+        self.data = data;
+        self.debug_info = program.debug_info;
+        self.code = program.code;
+        self.encoding = program.encoding;
+    }
+
+    //a copy of the state of the program, restore goes back to it
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            program: Program {
+                encoding: self.encoding,
+                code: self.code.clone(),
+                data: self.data.clone(),
+                debug_info: self.debug_info.clone(),
+            },
+            registers: self.registers,
+            counter: self.offsets[self.index],
+            stack: self.stack.to_vec(),
+            stack_pointer: self.stack_pointer,
+            heap: self.heap.clone(),
+            exit_code: self.exit_code,
+        }
+    }
+
+    //Loads the program of a snapshot and puts everything back the way it was when
+    //the snapshot was taken. The fuel starts over, as it does for a new program.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.new_program(snapshot.program.clone());
+        self.registers = snapshot.registers;
+        self.index = self.index_of.get(snapshot.counter).map_or(self.instructions.len(), |&index| index as usize);
+        self.stack.copy_from_slice(&snapshot.stack);
+        self.stack_pointer = snapshot.stack_pointer;
+        self.heap = snapshot.heap.clone();
+        self.exit_code = snapshot.exit_code;
+    }
+
+    pub fn registers(&self) -> &[u32; REGISTER_COUNT] {
+        &self.registers
+    }
+
+    //the values on the stack, from the bottom up
+    pub fn stack(&self) -> Vec<u32> {
+        self.stack[..self.stack_pointer * WORD_BYTES].chunks(WORD_BYTES).map(memory::read_le).collect()
+    }
+
+    //a copy of the len bytes at address, which must all be in the same region
+    pub fn read_memory(&mut self, address: usize, len: usize) -> Result<Vec<u8>, Trap> {
+        Ok(self.memory_at(address, len)?.to_vec())
+    }
+
+    pub fn heap(&self) -> &CVMHeap {
+        &self.heap
+    }
+
+    //runs the program until it ends, returns the code it gave exit, 0 if it didn't
+    pub fn run(&mut self) -> Result<i32, CVMError> {
+        //fuel that runs out right before the end of the code isn't missed
+        if self.run_on_fuel()? && self.index < self.instructions.len() {
+            return Err(self.trapped(self.index, Trap::OutOfFuel));
+        }
+        Ok(self.exit_code.unwrap_or(0))
+    }
+
+    //runs one instruction, tells if the program goes on after it
+    pub fn step(&mut self) -> Result<bool, CVMError> {
+        if self.index >= self.instructions.len() {
+            return Ok(false);
+        }
+        if self.fuel == 0 {
+            return Err(self.trapped(self.index, Trap::OutOfFuel));
+        }
+        //the fuel for a single instruction, the rest is given back after it
+        let fuel = self.fuel - 1;
+        self.fuel = 1;
+        let result = self.run_on_fuel();
+        self.fuel += fuel;
+        result
+    }
+
+    //Runs until the program ends or the fuel is used up, tells if it goes on. This is
+    //the only loop around execute_instruction, and checks nothing but the fuel, so
+    //that execute_instruction is inlined into a tight loop.
+    fn run_on_fuel(&mut self) -> Result<bool, CVMError> {
+        while self.fuel > 0 {
+            self.fuel -= 1;
+            let index = self.index;
+            match self.execute_instruction() {
+                Ok(true) => {}
+                Ok(false) => return Ok(false),
+                Err(trap) => return Err(self.trapped(index, trap)),
+            }
+        }
+        Ok(true)
+    }
+
+    fn trapped(&mut self, index: usize, trap: Trap) -> CVMError {
+        let pc = self.offsets[index];
+        let source = self.debug_info.lookup(pc).map(|location| location.to_string());
+        let error = CVMError { pc, trap, source };
+        self.tracer.trapped(&error);
+        error
     }
 
-    pub fn run(&mut self) {
-        let mut running = true;
-        while running {
-            running = self.execute_instruction();
+    //the offset and the instruction that runs next, None once the program has ended
+    pub fn next_instruction(&self) -> Option<(usize, Option<CI>)> {
+        self.instructions.get(self.index).map(|instruction| (self.offsets[self.index], *instruction))
+    }
+
+    //how the program ended, None if it ran past its last instruction
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
+    }
+
+    //Puts back what the instruction at pc changed, the last change first, and makes it
+    //the next to run again. What it printed stays printed.
+    fn undo(&mut self, pc: usize, changes: &[Change]) {
+        for change in changes.iter().rev() {
+            match *change {
+                Change::Register(register, value) => self.registers[register as usize] = value,
+                Change::Memory(address, len, value) => {
+                    if let Ok(bytes) = self.memory_at(address, len) {
+                        memory::write_le(bytes, value);
+                    }
+                }
+                Change::StackPointer(stack_pointer) => self.stack_pointer = stack_pointer,
+                Change::Allocated(offset) => self.heap.undo_alloc(offset),
+                Change::Freed(allocation) => self.heap.undo_free(allocation),
+            }
         }
+        self.index = self.index_of[pc] as usize;
+        self.exit_code = None;
+        self.fuel = (self.fuel + 1).min(self.fuel_limit);
     }
 
-    fn execute_instruction(&mut self) -> bool {
-        if self.counter >= self.program.len() {
-            return false;
+    //continues at the instruction starting at byte offset target, jumping to the end of the code halts
+    #[inline(always)]
+    fn jump(&mut self, target: usize) -> Result<(), Trap> {
+        match self.index_of.get(target) {
+            Some(&index) if index != NO_INSTRUCTION => {
+                self.index = index as usize;
+                Ok(())
+            }
+            _ => Err(Trap::InvalidJump(target)),
         }
-        let instruction = self.decode_opcode();
+    }
 
+    //inlined into run_on_fuel with execute, the traps they can return keep the compiler from doing it on its own
+    #[inline(always)]
+    fn execute_instruction(&mut self) -> Result<bool, Trap> {
+        let instruction = match self.instructions.get(self.index) {
+            Some(Some(instruction)) => *instruction,
+            Some(None) => return Err(Trap::InvalidInstruction(self.offsets[self.index])),
+            None => return Ok(false),
+        };
+        let pc = self.offsets[self.index];
+        self.index += 1;
+        self.tracer.before(pc, &instruction, &self.registers);
+        let running = self.execute(instruction, pc)?;
+        self.tracer.after(self.offsets[self.index], &self.registers);
+        Ok(running)
+    }
+
+    #[inline(always)]
+    fn execute(&mut self, instruction: CI, pc: usize) -> Result<bool, Trap> {
         match instruction {
-            Opcode::HLT => {
-                println!("Program terminated successfully");
-                return false;
-            }
-            Opcode::LOAD => {
-                let register = self.next_8_bits();
-                let value = self.next_32_bits();
-                self.registers[register as usize] = value;
-                self.next_16_bits();
-            }
-            Opcode::PRINT => {
-                let print_value = self.read_next_raw();
-                print!("{}", unsafe { transmute::<u32, char>(print_value) });
-                self.next_16_bits();
-            }
-            Opcode::INC => {
-                let rw_register = self.next_8_bits() as usize;
-                let val = unsafe { transmute::<i32, u32>(transmute::<u32, i32>(self.registers[rw_register]) + 1) };
-                self.registers[rw_register as usize] = val;
-                self.next_16_bits();
-            }
-            Opcode::DEC => {
-                let rw_register = self.next_8_bits() as usize;
-                let val = unsafe { transmute::<i32, u32>(transmute::<u32, i32>(self.registers[rw_register]) - 1) };
-                self.registers[rw_register as usize] = val;
-                self.next_16_bits();
-            }
-            Opcode::ADD => {
-                let register_0 = self.read_next_i32();
-                let register_1 = self.read_next_i32();
-                self.write_next_i32(register_0 + register_1);
-            }
-            Opcode::SUB => {
-                let register_0 = self.read_next_i32();
-                let register_1 = self.read_next_i32();
-                self.write_next_i32(register_0 - register_1);
-            }
-            Opcode::MUL => {
-                let register_0 = self.read_next_i32();
-                let register_1 = self.read_next_i32();
-                self.write_next_i32(register_0 * register_1);
-            }
-            Opcode::DIV => {
-                let register_0 = self.read_next_i32();
-                let register_1 = self.read_next_i32();
-                self.write_next_i32(register_0 / register_1);
-            }
-            Opcode::MOD => {
-                let register_0 = self.read_next_i32();
-                let register_1 = self.read_next_i32();
-                self.write_next_i32(register_0 % register_1);
-            }
-            Opcode::FADD => {
-                let register_0 = self.read_next_f32();
-                let register_1 = self.read_next_f32();
-                self.write_next_f32(register_0 + register_1);
-            }
-            Opcode::FSUB => {
-                let register_0 = self.read_next_f32();
-                let register_1 = self.read_next_f32();
-                self.write_next_f32(register_0 - register_1);
-            }
-            Opcode::FMUL => {
-                let register_0 = self.read_next_f32();
-                let register_1 = self.read_next_f32();
-                self.write_next_f32(register_0 * register_1);
-            }
-            Opcode::FDIV => {
-                let register_0 = self.read_next_f32();
-                let register_1 = self.read_next_f32();
-                self.write_next_f32(register_0 / register_1);
-            }
-            Opcode::JMP => {
-                let jump = self.read_next_i32();
-                self.next_16_bits();
-                self.counter = jump as usize;
-            }
-            Opcode::JMPF => {
-                let mut counter = self.counter;
-                let jump = self.read_next_i32();
-                counter += jump as usize;
-                self.next_16_bits();
-                self.next_8_bits();
-                self.counter = counter;
-            }
-            Opcode::JMPB => {
-                let mut counter = self.counter;
-                let jump = self.read_next_i32();
-                counter -= jump as usize;
-                self.next_16_bits();
-                self.next_8_bits();
-                self.counter = counter;
-            }
-            Opcode::EQ => {
-                let register_0 = self.read_next_i32();
-                let register_1 = self.read_next_i32();
-                self.write_next_raw(if register_0 == register_1 { 1 } else { 0 });
-            }
-            Opcode::NE => {
-                let register_0 = self.read_next_i32();
-                let register_1 = self.read_next_i32();
-                self.write_next_raw(if register_0 != register_1 { 1 } else { 0 });
-            }
-            Opcode::GT => {
-                let register_0 = self.read_next_i32();
-                let register_1 = self.read_next_i32();
-                self.write_next_raw(if register_0 > register_1 { 1 } else { 0 });
-            }
-            Opcode::LT => {
-                let register_0 = self.read_next_i32();
-                let register_1 = self.read_next_i32();
-                self.write_next_raw(if register_0 < register_1 { 1 } else { 0 });
-            }
-            Opcode::GTQ => {
-                let register_0 = self.read_next_i32();
-                let register_1 = self.read_next_i32();
-                self.write_next_raw(if register_0 >= register_1 { 1 } else { 0 });
-            }
-            Opcode::LTQ => {
-                let register_0 = self.read_next_i32();
-                let register_1 = self.read_next_i32();
-                self.write_next_raw(if register_0 <= register_1 { 1 } else { 0 });
-            }
-            Opcode::FEQ => {
-                let register_0 = self.read_next_f32();
-                let register_1 = self.read_next_f32();
-                self.write_next_raw(if register_0 == register_1 { 1 } else { 0 });
-            }
-            Opcode::FNE => {
-                let register_0 = self.read_next_f32();
-                let register_1 = self.read_next_f32();
-                self.write_next_raw(if register_0 != register_1 { 1 } else { 0 });
-            }
-            Opcode::FGT => {
-                let register_0 = self.read_next_f32();
-                let register_1 = self.read_next_f32();
-                self.write_next_raw(if register_0 > register_1 { 1 } else { 0 });
-            }
-            Opcode::FLT => {
-                let register_0 = self.read_next_f32();
-                let register_1 = self.read_next_f32();
-                self.write_next_raw(if register_0 < register_1 { 1 } else { 0 });
-            }
-            Opcode::FGTQ => {
-                let register_0 = self.read_next_f32();
-                let register_1 = self.read_next_f32();
-                self.write_next_raw(if register_0 >= register_1 { 1 } else { 0 });
-            }
-            Opcode::FLTQ => {
-                let register_0 = self.read_next_f32();
-                let register_1 = self.read_next_f32();
-                self.write_next_raw(if register_0 <= register_1 { 1 } else { 0 });
-            }
-            Opcode::JEQ => {
-                let register_0 = self.read_next_raw();
-                let register_1 = self.read_next_raw();
-                self.next_8_bits();
-                if register_0 != 0 {
-                    self.counter = register_1 as usize;
+            CI::HLT => {
+                self.exit_code = Some(0);
+                return Ok(false);
+            }
+            CI::EXIT(register) => {
+                self.exit_code = Some(self.int(register));
+                return Ok(false);
+            }
+            CI::LOAD(register, value) => {
+                self.set_raw(register, value);
+            }
+            CI::PRINT(register) => {
+                let print_value = self.raw(register);
+                self.print(format_args!("{}", std::char::from_u32(print_value).unwrap_or(std::char::REPLACEMENT_CHARACTER)));
+            }
+            CI::PRINTS(register) => {
+                let mut address = self.raw(register) as usize;
+                let mut string = vec![];
+                loop {
+                    let byte = self.load(address, 1)? as u8;
+                    if byte == 0 {
+                        break;
+                    }
+                    string.push(byte);
+                    address += 1;
+                }
+                self.print(format_args!("{}", String::from_utf8_lossy(&string)));
+            }
+            CI::INC(register) => {
+                self.set_int(register, self.int(register).wrapping_add(1));
+            }
+            CI::DEC(register) => {
+                self.set_int(register, self.int(register).wrapping_sub(1));
+            }
+            CI::ADD(register_0, register_1, out) => {
+                self.set_int(out, self.int(register_0).wrapping_add(self.int(register_1)));
+            }
+            CI::SUB(register_0, register_1, out) => {
+                self.set_int(out, self.int(register_0).wrapping_sub(self.int(register_1)));
+            }
+            CI::MUL(register_0, register_1, out) => {
+                self.set_int(out, self.int(register_0).wrapping_mul(self.int(register_1)));
+            }
+            CI::DIV(register_0, register_1, out) => {
+                if self.int(register_1) == 0 {
+                    return Err(Trap::DivideByZero);
                 }
+                self.set_int(out, self.int(register_0).wrapping_div(self.int(register_1)));
+            }
+            CI::MOD(register_0, register_1, out) => {
+                if self.int(register_1) == 0 {
+                    return Err(Trap::DivideByZero);
+                }
+                self.set_int(out, self.int(register_0).wrapping_rem(self.int(register_1)));
+            }
+            CI::FADD(register_0, register_1, out) => {
+                self.set_float(out, self.float(register_0) + self.float(register_1));
+            }
+            CI::FSUB(register_0, register_1, out) => {
+                self.set_float(out, self.float(register_0) - self.float(register_1));
+            }
+            CI::FMUL(register_0, register_1, out) => {
+                self.set_float(out, self.float(register_0) * self.float(register_1));
+            }
+            CI::FDIV(register_0, register_1, out) => {
+                self.set_float(out, self.float(register_0) / self.float(register_1));
+            }
+            CI::JMP(register) => {
+                self.jump(self.raw(register) as usize)?;
+            }
+            //Relative jumps count from the byte after the opcode. The target is worked out
+            //in 32 bits like every other address, one before the start of the code wraps
+            //around past its end and traps.
+            CI::JMPF(register) => {
+                self.jump((pc as u32).wrapping_add(1).wrapping_add(self.raw(register)) as usize)?;
+            }
+            CI::JMPB(register) => {
+                self.jump((pc as u32).wrapping_add(1).wrapping_sub(self.raw(register)) as usize)?;
+            }
+            CI::EQ(register_0, register_1, out) => {
+                self.set_raw(out, (self.int(register_0) == self.int(register_1)) as u32);
+            }
+            CI::NE(register_0, register_1, out) => {
+                self.set_raw(out, (self.int(register_0) != self.int(register_1)) as u32);
+            }
+            CI::GT(register_0, register_1, out) => {
+                self.set_raw(out, (self.int(register_0) > self.int(register_1)) as u32);
+            }
+            CI::LT(register_0, register_1, out) => {
+                self.set_raw(out, (self.int(register_0) < self.int(register_1)) as u32);
+            }
+            CI::GTQ(register_0, register_1, out) => {
+                self.set_raw(out, (self.int(register_0) >= self.int(register_1)) as u32);
+            }
+            CI::LTQ(register_0, register_1, out) => {
+                self.set_raw(out, (self.int(register_0) <= self.int(register_1)) as u32);
+            }
+            CI::FEQ(register_0, register_1, out) => {
+                self.set_raw(out, (self.float(register_0) == self.float(register_1)) as u32);
+            }
+            CI::FNE(register_0, register_1, out) => {
+                self.set_raw(out, (self.float(register_0) != self.float(register_1)) as u32);
             }
-            Opcode::JNE => {
-                let register_0 = self.read_next_raw();
-                let register_1 = self.read_next_raw();
-                self.next_8_bits();
-                if register_0 == 0 {
-                    self.counter = register_1 as usize;
+            CI::FGT(register_0, register_1, out) => {
+                self.set_raw(out, (self.float(register_0) > self.float(register_1)) as u32);
+            }
+            CI::FLT(register_0, register_1, out) => {
+                self.set_raw(out, (self.float(register_0) < self.float(register_1)) as u32);
+            }
+            CI::FGTQ(register_0, register_1, out) => {
+                self.set_raw(out, (self.float(register_0) >= self.float(register_1)) as u32);
+            }
+            CI::FLTQ(register_0, register_1, out) => {
+                self.set_raw(out, (self.float(register_0) <= self.float(register_1)) as u32);
+            }
+            CI::JEQ(check, jump) => {
+                if self.raw(check) != 0 {
+                    self.jump(self.raw(jump) as usize)?;
                 }
             }
-            Opcode::PUSH => {
-                let value = self.read_next_raw();
-                if self.stack_pointer >= self.stack.len() {
-                    println!("CVM stack overflow");
-                    return false;
+            CI::JNE(check, jump) => {
+                if self.raw(check) == 0 {
+                    self.jump(self.raw(jump) as usize)?;
                 }
-                self.stack[self.stack_pointer] = value;
-                self.stack_pointer += 1;
-                self.next_16_bits();
-            }
-            Opcode::SPUSH => {
-                let amount = self.read_next_raw() as usize;
-                self.stack_pointer += amount;
-                if self.stack_pointer > self.stack.len() {
-                    println!("CVM stack overflow");
-                    return false;
+            }
+            CI::PUSH(register) => {
+                if self.stack_pointer >= self.stack_limit {
+                    return Err(Trap::StackOverflow);
                 }
-                self.next_16_bits();
-            }
-            Opcode::POP => {
-                self.stack_pointer -= 1;
-                if let Some(&value) = self.stack.get(self.stack_pointer) {
-                    self.write_next_raw(value);
-                    self.next_8_bits();
-                } else {
-                    println!("CVM stack underflow");
-                    return false;
+                self.set_stack_slot(self.stack_pointer, self.raw(register))?;
+                self.set_stack_pointer(self.stack_pointer + 1);
+            }
+            CI::SPUSH(register) => {
+                let stack_pointer = self.stack_pointer.checked_add(self.raw(register) as usize)
+                    .filter(|&stack_pointer| stack_pointer <= self.stack_limit)
+                    .ok_or(Trap::StackOverflow)?;
+                self.set_stack_pointer(stack_pointer);
+            }
+            CI::POP(register) => {
+                if self.stack_pointer == 0 {
+                    return Err(Trap::StackUnderflow);
                 }
+                self.set_stack_pointer(self.stack_pointer - 1);
+                let value = self.stack_slot(self.stack_pointer)?;
+                self.set_raw(register, value);
             }
-            Opcode::SPOP => {
-                let amount = self.read_next_raw() as usize;
+            CI::SPOP(register) => {
+                let amount = self.raw(register) as usize;
                 if amount > self.stack_pointer {
-                    println!("CVM stack underflow");
-                    return false;
+                    return Err(Trap::StackUnderflow);
                 }
-                self.stack_pointer -= amount;
-                self.next_16_bits();
-            }
-            Opcode::READ => {
-                let index = self.read_next_raw() as usize;
-                let value = if index >= STACK_SIZE {
-                    *self.heap.value_at(index - STACK_SIZE)
-                } else {
-                    self.stack[index]
-                };
-                self.write_next_raw(value);
-                self.next_8_bits();
-            }
-            Opcode::WRITE => {
-                let read_register = self.next_8_bits() as usize;
-                let index = self.read_next_raw() as usize;
-                if index >= STACK_SIZE {
-                    *self.heap.value_at(index - STACK_SIZE) = self.registers[read_register]
-                } else {
-                    self.stack[index] = self.registers[read_register]
+                self.set_stack_pointer(self.stack_pointer - amount);
+            }
+            CI::READ(address, out) => {
+                let value = self.load(self.raw(address) as usize, 4)?;
+                self.set_raw(out, value);
+            }
+            CI::LOAD8U(address, out) => {
+                let value = self.load(self.raw(address) as usize, 1)?;
+                self.set_raw(out, value);
+            }
+            CI::LOAD8S(address, out) => {
+                let value = self.load(self.raw(address) as usize, 1)? as u8 as i8;
+                self.set_int(out, value as i32);
+            }
+            CI::LOAD16U(address, out) => {
+                let value = self.load(self.raw(address) as usize, 2)?;
+                self.set_raw(out, value);
+            }
+            CI::LOAD16S(address, out) => {
+                let value = self.load(self.raw(address) as usize, 2)? as u16 as i16;
+                self.set_int(out, value as i32);
+            }
+            CI::WRITE(value, address) => {
+                self.store(self.raw(address) as usize, 4, self.raw(value))?;
+            }
+            CI::STORE8(value, address) => {
+                self.store(self.raw(address) as usize, 1, self.raw(value))?;
+            }
+            CI::STORE16(value, address) => {
+                self.store(self.raw(address) as usize, 2, self.raw(value))?;
+            }
+            CI::SREAD(index, out) => {
+                let value = self.stack_slot(self.raw(index) as usize)?;
+                self.set_raw(out, value);
+            }
+            CI::SWRITE(value, index) => {
+                self.set_stack_slot(self.raw(index) as usize, self.raw(value))?;
+            }
+            CI::MOV(register, out) => {
+                self.set_raw(out, self.raw(register));
+            }
+            CI::MALLOC(size, out) => {
+                //a negative size is taken as the unsigned value, which never fits
+                let offset = self.heap.alloc(self.raw(size) as usize, pc)?;
+                self.tracer.changed(Change::Allocated(offset));
+                self.set_raw(out, (offset + HEAP_BASE) as u32);
+            }
+            CI::FREE(register) => {
+                let pointer = self.raw(register) as usize;
+                let freed = match memory::resolve(pointer)? {
+                    Region::Heap(offset) => self.heap.free(offset),
+                    _ => None,
                 };
-                self.next_8_bits();
-            }
-            Opcode::MOV => {
-                let value = self.read_next_raw();
-                self.write_next_raw(value);
-                self.next_8_bits();
-            }
-            Opcode::MALLOC => {
-                let read_register = self.read_next_i32() as usize;
-                let pointer = self.heap.alloc(read_register) as u32;
-                self.write_next_raw(pointer);
-                self.next_8_bits();
-            }
-            Opcode::FREE => {
-                let read_register = self.read_next_i32() as usize;
-                self.heap.free(read_register);
-                self.next_16_bits();
-            }
-            Opcode::FTOI => {
-                let read_register = self.read_next_raw();
-                let new_val = unsafe { transmute::<u32, f32>(read_register) } as i32;
-                self.write_next_i32(new_val);
-                self.next_8_bits();
-            }
-            Opcode::ITOF => {
-                let read_register = self.read_next_raw();
-                let new_val = unsafe { transmute::<u32, i32>(read_register) } as f32;
-                self.write_next_f32(new_val);
-                self.next_8_bits();
-            }
-            Opcode::I32 => {
-                let read_register = self.read_next_raw();
-                let value = read_register as i32;
-                self.write_next_i32(value);
-                self.next_8_bits();
-            }
-            Opcode::F32 => {
-                let read_register = self.read_next_raw();
-                let value = read_register as f32;
-                self.write_next_f32(value);
-                self.next_8_bits();
+                match freed {
+                    Some(allocation) => self.tracer.changed(Change::Freed(allocation)),
+                    None => return Err(Trap::InvalidFree(pointer)),
+                }
+            }
+            CI::FTOI(register, out) => {
+                self.set_int(out, self.float(register) as i32);
+            }
+            CI::ITOF(register, out) => {
+                self.set_float(out, self.int(register) as f32);
+            }
+            CI::I32(register, out) => {
+                self.set_int(out, self.raw(register) as i32);
+            }
+            CI::F32(register, out) => {
+                self.set_float(out, self.raw(register) as f32);
             }
         }
-        true
+        Ok(true)
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.counter];
-        self.counter += 1;
-        return result;
+    //the bytes in [address, address + len), which must all be in the same region
+    fn memory_at(&mut self, address: usize, len: usize) -> Result<&mut [u8], Trap> {
+        let bytes = match memory::resolve(address)? {
+            Region::Stack(offset) => self.stack.get_mut(offset..offset + len),
+            Region::Data(offset) => self.data.get_mut(offset..offset + len),
+            Region::Heap(offset) => self.heap.bytes_at(offset, len),
+        };
+        bytes.ok_or(Trap::Unmapped(address))
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let out = Self::u16_le(&self.program[self.counter..self.counter + 2]);
-        self.counter += 2;
-        out
+    fn load(&mut self, address: usize, len: usize) -> Result<u32, Trap> {
+        Ok(memory::read_le(self.memory_at(address, len)?))
     }
 
-    fn next_32_bits(&mut self) -> u32 {
-        let out = Self::u32_le(&self.program[self.counter..self.counter + 4]);
-        self.counter += 4;
-        out
+    fn store(&mut self, address: usize, len: usize, value: u32) -> Result<(), Trap> {
+        let bytes = self.memory_at(address, len)?;
+        let old = memory::read_le(bytes);
+        memory::write_le(bytes, value);
+        self.tracer.changed(Change::Memory(address, len, old));
+        Ok(())
     }
 
-    fn u16_le(array: &[u8]) -> u16 {
-        ((array[0] as u16) << 0) +
-            ((array[1] as u16) << 8)
+    fn stack_slot(&mut self, index: usize) -> Result<u32, Trap> {
+        self.load(STACK_BASE + index * WORD_BYTES, WORD_BYTES)
     }
 
-    fn u32_le(array: &[u8]) -> u32 {
-        ((array[0] as u32) << 0) +
-            ((array[1] as u32) << 8) +
-            ((array[2] as u32) << 16) +
-            ((array[3] as u32) << 24)
+    fn set_stack_slot(&mut self, index: usize, value: u32) -> Result<(), Trap> {
+        self.store(STACK_BASE + index * WORD_BYTES, WORD_BYTES, value)
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = unsafe { transmute::<u8, Opcode>(self.program[self.counter]) };
-        self.counter += 1;
-        return opcode;
+    //kept out of execute_instruction, inlined it slows down the run loop
+    #[inline(never)]
+    fn print(&mut self, text: fmt::Arguments) {
+        match &mut self.output {
+            //writing to a Vec can't fail
+            Some(output) => {
+                let _ = output.write_fmt(text);
+            }
+            None => print!("{}", text),
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet_assembler::assembler::CarpetAssembler;
+    use crate::parser::parse::Parser;
+
+    fn loaded(source: &str) -> CVM {
+        let module = Parser::new().parse_source("test.cbc", source).unwrap();
+        let mut cvm = CVM::new().with_stack_size(4).with_captured_output();
+        cvm.new_program(CarpetAssembler::new().assemble(module).unwrap());
+        cvm
+    }
+
+    #[test]
+    fn stack_overflow_leaves_the_stack_pointer() {
+        let cases = [
+            ("loadi r1 7\npush r1\nloadi r2 100000\nspush r2\n", vec![7]),
+            ("loadi r1 -1\nspush r1\n", vec![]),
+            ("loadi r1 3\nspush r1\nloadi r1 9\npush r1\npush r1\n", vec![0, 0, 0, 9]),
+        ];
+        for (source, stack) in cases {
+            let mut cvm = loaded(source);
+            assert_eq!(cvm.run().unwrap_err().trap, Trap::StackOverflow, "{}", source);
+            assert_eq!(cvm.stack(), stack, "{}", source);
+        }
+    }
+
+    #[test]
+    fn stack_underflow_leaves_the_stack_pointer() {
+        let mut cvm = loaded("loadi r1 7\npush r1\nloadi r2 2\nspop r2\n");
+        assert_eq!(cvm.run().unwrap_err().trap, Trap::StackUnderflow);
+        assert_eq!(cvm.stack(), vec![7]);
+    }
+}
@@ -1,48 +1,280 @@
-const HEAP_SIZE: usize = 65536;
+use crate::carpet::binary::{self, Reader};
+use crate::carpet::cvm::Trap;
+use std::error::Error;
+use std::fmt;
 
-#[derive(Debug)]
+//in bytes, the most the heap region of the address space holds
+pub const HEAP_SIZE: usize = 0x40000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Allocation {
+    pub ptr: usize,
+    pub size: usize,
+    //program counter of the MALLOC that made this allocation
+    pub origin: usize,
+}
+
+impl Allocation {
+    fn end(&self) -> usize {
+        self.ptr + self.size
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub live_allocations: usize,
+    pub bytes_in_use: usize,
+    pub peak_bytes_in_use: usize,
+    pub total_allocations: usize,
+    pub total_frees: usize,
+    pub largest_free_block: usize,
+    pub total_free: usize,
+}
+
+impl HeapStats {
+    //0 when all free memory is one block, approaches 1 as it gets split up
+    pub fn fragmentation(&self) -> f32 {
+        if self.total_free == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free_block as f32 / self.total_free as f32
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} live allocations, {} bytes in use (peak {} bytes)",
+            self.live_allocations, self.bytes_in_use, self.peak_bytes_in_use
+        )?;
+        writeln!(f, "{} allocations, {} frees", self.total_allocations, self.total_frees)?;
+        write!(
+            f,
+            "largest free block {} of {} free bytes (fragmentation {:.1}%)",
+            self.largest_free_block,
+            self.total_free,
+            self.fragmentation() * 100.0
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct CVMHeap {
-    heap: Vec<u32>,
-    sizes: Vec<(usize, usize)>,
+    heap: Vec<u8>,
+    allocations: Vec<Allocation>,
+    peak_in_use: usize,
+    total_allocations: usize,
+    total_frees: usize,
 }
 
 impl CVMHeap {
     pub fn new() -> Self {
+        CVMHeap::with_size(HEAP_SIZE)
+    }
+
+    //a heap of size bytes, at most HEAP_SIZE, addresses past its end are unmapped
+    pub fn with_size(size: usize) -> Self {
         Self {
-            heap: vec![0u32; HEAP_SIZE],
-            sizes: vec![(HEAP_SIZE - 1, 0)],
+            heap: vec![0u8; size.min(HEAP_SIZE)],
+            allocations: vec![],
+            peak_in_use: 0,
+            total_allocations: 0,
+            total_frees: 0,
         }
     }
 
-    pub fn value_at(&mut self, ptr: usize) -> &mut u32 {
-        &mut self.heap[ptr]
+    //frees everything and zeroes the bytes, the heap keeps its size
+    pub fn reset(&mut self) {
+        *self = CVMHeap::with_size(self.heap.len());
     }
 
-    pub fn alloc(&mut self, size: usize) -> usize {
-        let mut alloc_index = 0;
-        for &(ptr, size) in &self.sizes {
-            let dist_to_ptr = ptr - alloc_index;
-            if size < dist_to_ptr {
+    //None if the range runs past the end of the heap
+    pub fn bytes_at(&mut self, ptr: usize, len: usize) -> Option<&mut [u8]> {
+        self.heap.get_mut(ptr..ptr + len)
+    }
+
+    //first fit, a size of 0 takes a byte so that every pointer is a different one
+    pub fn alloc(&mut self, size: usize, origin: usize) -> Result<usize, Trap> {
+        let size = size.max(1);
+        let mut alloc_index: usize = 0;
+        for allocation in &self.allocations {
+            if alloc_index.checked_add(size).is_some_and(|end| end <= allocation.ptr) {
                 break;
             }
-            alloc_index = ptr + size;
+            alloc_index = allocation.end();
         }
-        if alloc_index >= HEAP_SIZE - 1 {
-            panic!("CVM out of Heap");
+        if alloc_index.checked_add(size).is_none_or(|end| end > self.heap.len()) {
+            return Err(Trap::OutOfHeap(size));
         }
-        let insert_pos = self.sizes.binary_search_by_key(
+        let insert_pos = self.allocations.binary_search_by_key(
             &alloc_index,
-            |&(ptr, _)| ptr,
+            |allocation| allocation.ptr,
         ).unwrap_or_else(|index| index);
-        self.sizes.insert(insert_pos, (alloc_index, size));
-        alloc_index
+        self.allocations.insert(insert_pos, Allocation { ptr: alloc_index, size, origin });
+        self.total_allocations += 1;
+        self.peak_in_use = self.peak_in_use.max(self.bytes_in_use());
+        Ok(alloc_index)
     }
 
-    pub fn free(&mut self, pointer: usize) {
-        let index = self.sizes.iter().position(
-            |(ptr, _)| *ptr == pointer
+    //returns what was freed, None if nothing is allocated at the pointer, e.g. when it has already been freed
+    pub fn free(&mut self, pointer: usize) -> Option<Allocation> {
+        let index = self.allocations.iter().position(
+            |allocation| allocation.ptr == pointer
         );
-        let index = index.expect("already free");
-        self.sizes.remove(index);
+        let allocation = self.allocations.remove(index?);
+        self.total_frees += 1;
+        Some(allocation)
+    }
+
+    //takes back the alloc that returned pointer, the peak stays as it is
+    pub fn undo_alloc(&mut self, pointer: usize) {
+        if let Ok(index) = self.allocations.binary_search_by_key(&pointer, |allocation| allocation.ptr) {
+            self.allocations.remove(index);
+            self.total_allocations -= 1;
+        }
+    }
+
+    //takes back the free of allocation
+    pub fn undo_free(&mut self, allocation: Allocation) {
+        if let Err(index) = self.allocations.binary_search_by_key(&allocation.ptr, |allocation| allocation.ptr) {
+            self.allocations.insert(index, allocation);
+            self.total_frees -= 1;
+        }
+    }
+
+    //every allocation that is still live, which at HLT means it was never freed
+    pub fn leaks(&self) -> &[Allocation] {
+        &self.allocations
     }
-}
\ No newline at end of file
+
+    pub fn stats(&self) -> HeapStats {
+        let mut largest_free_block = 0;
+        let mut free_start = 0;
+        for allocation in &self.allocations {
+            largest_free_block = largest_free_block.max(allocation.ptr - free_start);
+            free_start = free_start.max(allocation.end());
+        }
+        largest_free_block = largest_free_block.max(self.heap.len() - free_start);
+        let in_use = self.bytes_in_use();
+        HeapStats {
+            live_allocations: self.allocations.len(),
+            bytes_in_use: in_use,
+            peak_bytes_in_use: self.peak_in_use,
+            total_allocations: self.total_allocations,
+            total_frees: self.total_frees,
+            largest_free_block,
+            total_free: self.heap.len() - in_use,
+        }
+    }
+
+    //the size, the bytes up to the last one that isn't 0, the allocations and the counts of stats
+    pub fn write(&self, out: &mut Vec<u8>) {
+        binary::write_u32(out, self.heap.len() as u32);
+        binary::write_bytes(out, binary::trim_zeros(&self.heap));
+        binary::write_u32(out, self.allocations.len() as u32);
+        for allocation in &self.allocations {
+            binary::write_u32(out, allocation.ptr as u32);
+            binary::write_u32(out, allocation.size as u32);
+            binary::write_u32(out, allocation.origin as u32);
+        }
+        binary::write_u32(out, self.peak_in_use as u32);
+        binary::write_u32(out, self.total_allocations as u32);
+        binary::write_u32(out, self.total_frees as u32);
+    }
+
+    pub fn read(reader: &mut Reader) -> Result<Self, Box<dyn Error>> {
+        let size = reader.u32()? as usize;
+        if size > HEAP_SIZE {
+            return Err(format!("a heap of {} bytes is larger than {}", size, HEAP_SIZE).into());
+        }
+        let mut heap = reader.bytes()?;
+        if heap.len() > size {
+            return Err(format!("{} bytes don't fit into a heap of {}", heap.len(), size).into());
+        }
+        heap.resize(size, 0);
+        let mut allocations: Vec<Allocation> = vec![];
+        for _ in 0..reader.u32()? {
+            let allocation = Allocation {
+                ptr: reader.u32()? as usize,
+                size: reader.u32()? as usize,
+                origin: reader.u32()? as usize,
+            };
+            //allocations are kept in order and can't overlap, alloc relies on it
+            let after_last = allocations.last().is_none_or(|last| last.end() <= allocation.ptr);
+            if !after_last || allocation.size == 0 || allocation.end() > size {
+                return Err(format!("invalid allocation of {} bytes at {:#x}", allocation.size, allocation.ptr).into());
+            }
+            allocations.push(allocation);
+        }
+        Ok(Self {
+            heap,
+            allocations,
+            peak_in_use: reader.u32()? as usize,
+            total_allocations: reader.u32()? as usize,
+            total_frees: reader.u32()? as usize,
+        })
+    }
+
+    fn bytes_in_use(&self) -> usize {
+        self.allocations.iter().map(|allocation| allocation.size).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_fit_reuses_freed_blocks() {
+        let mut heap = CVMHeap::with_size(64);
+        assert_eq!(heap.alloc(16, 0), Ok(0));
+        assert_eq!(heap.alloc(8, 0), Ok(16));
+        assert_eq!(heap.alloc(8, 0), Ok(24));
+        assert_eq!(heap.free(0).map(|allocation| allocation.size), Some(16));
+        //too big for the hole at the start, goes after the last block
+        assert_eq!(heap.alloc(20, 0), Ok(32));
+        assert_eq!(heap.alloc(12, 0), Ok(0));
+        assert_eq!(heap.alloc(4, 0), Ok(12));
+        assert_eq!(heap.alloc(20, 0), Err(Trap::OutOfHeap(20)));
+    }
+
+    #[test]
+    fn free_needs_the_start_of_a_live_block() {
+        let mut heap = CVMHeap::with_size(64);
+        heap.alloc(8, 4).unwrap();
+        assert_eq!(heap.free(4), None);
+        assert_eq!(heap.free(0), Some(Allocation { ptr: 0, size: 8, origin: 4 }));
+        assert_eq!(heap.free(0), None);
+        assert!(heap.leaks().is_empty());
+    }
+
+    #[test]
+    fn size_zero_gets_its_own_pointer() {
+        let mut heap = CVMHeap::with_size(64);
+        assert_eq!(heap.alloc(4, 0), Ok(0));
+        assert_eq!(heap.alloc(0, 0), Ok(4));
+        assert_eq!(heap.alloc(0, 0), Ok(5));
+        assert_eq!(heap.free(4).map(|allocation| allocation.size), Some(1));
+        assert_eq!(heap.leaks().iter().map(|allocation| allocation.ptr).collect::<Vec<_>>(), vec![0, 5]);
+    }
+
+    #[test]
+    fn stats_keep_the_peak() {
+        let mut heap = CVMHeap::with_size(100);
+        heap.alloc(10, 0).unwrap();
+        heap.alloc(30, 0).unwrap();
+        heap.alloc(20, 0).unwrap();
+        heap.free(10).unwrap();
+        let stats = heap.stats();
+        assert_eq!(stats.live_allocations, 2);
+        assert_eq!(stats.bytes_in_use, 30);
+        assert_eq!(stats.peak_bytes_in_use, 60);
+        assert_eq!((stats.total_allocations, stats.total_frees), (3, 1));
+        assert_eq!((stats.largest_free_block, stats.total_free), (40, 70));
+        assert!((stats.fragmentation() - 3.0 / 7.0).abs() < 1e-6);
+        heap.reset();
+        assert_eq!(heap.stats().peak_bytes_in_use, 0);
+        assert_eq!(heap.stats().largest_free_block, 100);
+    }
+}
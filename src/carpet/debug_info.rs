@@ -0,0 +1,98 @@
+use crate::carpet::binary::{self, Reader};
+use std::error::Error;
+use std::fmt;
+
+//where the instruction starting at a byte offset of the code was written
+#[derive(Debug, Clone)]
+pub struct LineEntry {
+    pub offset: usize,
+    //index into DebugInfo::files
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+    //the line as written, for an instruction from a macro the line calling it
+    pub text: String,
+}
+
+//Maps code offsets back to the source they were assembled from. Entries are
+//kept sorted by offset, a program without debug info simply has none.
+#[derive(Debug, Clone, Default)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    pub lines: Vec<LineEntry>,
+}
+
+//the source of one instruction, as printed in errors
+pub struct SourceLocation<'a> {
+    pub path: &'a str,
+    pub entry: &'a LineEntry,
+}
+
+impl fmt::Display for SourceLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.path, self.entry.line, self.entry.text)
+    }
+}
+
+impl DebugInfo {
+    //entries have to be added in the order of their offsets
+    pub fn add(&mut self, offset: usize, path: &str, line: usize, column: usize, text: &str) {
+        let file = match self.files.iter().position(|file| file == path) {
+            Some(file) => file,
+            None => {
+                self.files.push(path.to_string());
+                self.files.len() - 1
+            }
+        };
+        self.lines.push(LineEntry { offset, file, line, column, text: text.to_string() });
+    }
+
+    //the entries of other, moved to start at base, go after the ones already here
+    pub fn append(&mut self, other: &DebugInfo, base: usize) {
+        for entry in &other.lines {
+            self.add(base + entry.offset, &other.files[entry.file], entry.line, entry.column, &entry.text);
+        }
+    }
+
+    //the instruction that the byte at pc belongs to
+    pub fn lookup(&self, pc: usize) -> Option<SourceLocation<'_>> {
+        let index = self.lines.partition_point(|entry| entry.offset <= pc).checked_sub(1)?;
+        let entry = &self.lines[index];
+        Some(SourceLocation { path: &self.files[entry.file], entry })
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        binary::write_u32(out, self.files.len() as u32);
+        for file in &self.files {
+            binary::write_str(out, file);
+        }
+        binary::write_u32(out, self.lines.len() as u32);
+        for entry in &self.lines {
+            binary::write_u32(out, entry.offset as u32);
+            binary::write_u32(out, entry.file as u32);
+            binary::write_u32(out, entry.line as u32);
+            binary::write_u32(out, entry.column as u32);
+            binary::write_str(out, &entry.text);
+        }
+    }
+
+    pub fn read(reader: &mut Reader) -> Result<Self, Box<dyn Error>> {
+        let mut files = vec![];
+        for _ in 0..reader.u32()? {
+            files.push(reader.string()?);
+        }
+        let mut lines = vec![];
+        for _ in 0..reader.u32()? {
+            let offset = reader.u32()? as usize;
+            let file = reader.u32()? as usize;
+            if file >= files.len() {
+                return Err(format!("debug info refers to file {} of {}", file, files.len()).into());
+            }
+            let line = reader.u32()? as usize;
+            let column = reader.u32()? as usize;
+            let text = reader.string()?;
+            lines.push(LineEntry { offset, file, line, column, text });
+        }
+        Ok(Self { files, lines })
+    }
+}
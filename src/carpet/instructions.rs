@@ -1,3 +1,9 @@
+use crate::carpet::cvm::REGISTER_COUNT;
+use crate::carpet::memory;
+use std::fmt;
+
+pub type Register = u8;
+
 #[derive(Debug, PartialEq)]
 #[repr(u8)]
 pub enum Opcode {
@@ -74,9 +80,9 @@ pub enum Opcode {
     SPOP,
     //SPop(8), Register(8)
     READ,
-    //SREAD(8), Register(8)
+    //READ(8), Register(8), Register(8)
     WRITE,
-    //SREAD(8), Register(8), Register(8)
+    //WRITE(8), Register(8), Register(8)
     MALLOC,
     //ALLOC(8), Register(8), Register(8)
     FREE,
@@ -85,4 +91,339 @@ pub enum Opcode {
     FTOI,
     I32,
     F32,
-}
\ No newline at end of file
+    SREAD,
+    //SREAD(8), Register(8), Register(8)
+    SWRITE,
+    //SWRITE(8), Register(8), Register(8)
+    LOAD8U,
+    //LOAD8U(8), Register(8), Register(8)
+    LOAD8S,
+    //LOAD8S(8), Register(8), Register(8)
+    LOAD16U,
+    //LOAD16U(8), Register(8), Register(8)
+    LOAD16S,
+    //LOAD16S(8), Register(8), Register(8)
+    STORE8,
+    //STORE8(8), Register(8), Register(8)
+    STORE16,
+    //STORE16(8), Register(8), Register(8)
+    PRINTS,
+    //PRINTS(8), Register(8)
+    EXIT,
+    //EXIT(8), Register(8)
+}
+
+impl Opcode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        if value > Opcode::EXIT as u8 {
+            return None;
+        }
+        //the opcodes are numbered from 0 without gaps, EXIT being the last
+        Some(unsafe { std::mem::transmute::<u8, Opcode>(value) })
+    }
+}
+
+//How instructions are laid out in byte code. Padded makes every instruction 4
+//bytes and LOAD 8, compact drops the padding, so an instruction is its opcode
+//followed by its operands.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[repr(u8)]
+pub enum Encoding {
+    #[default]
+    Padded = 0,
+    Compact,
+}
+
+impl Encoding {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Encoding::Padded),
+            1 => Some(Encoding::Compact),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CI {
+    LOAD(Register, u32),
+    PRINT(Register),
+    INC(Register),
+    DEC(Register),
+    ADD(Register, Register, Register),
+    SUB(Register, Register, Register),
+    MUL(Register, Register, Register),
+    DIV(Register, Register, Register),
+    MOD(Register, Register, Register),
+    FADD(Register, Register, Register),
+    FSUB(Register, Register, Register),
+    FMUL(Register, Register, Register),
+    FDIV(Register, Register, Register),
+    HLT,
+    JMP(Register),
+    JMPB(Register),
+    JMPF(Register),
+    EQ(Register, Register, Register),
+    NE(Register, Register, Register),
+    GT(Register, Register, Register),
+    LT(Register, Register, Register),
+    GTQ(Register, Register, Register),
+    LTQ(Register, Register, Register),
+    FEQ(Register, Register, Register),
+    FNE(Register, Register, Register),
+    FGT(Register, Register, Register),
+    FLT(Register, Register, Register),
+    FGTQ(Register, Register, Register),
+    FLTQ(Register, Register, Register),
+    PUSH(Register),
+    SPUSH(Register),
+    POP(Register),
+    SPOP(Register),
+    SREAD(Register, Register),
+    SWRITE(Register, Register),
+    READ(Register, Register),
+    WRITE(Register, Register),
+    LOAD8U(Register, Register),
+    LOAD8S(Register, Register),
+    LOAD16U(Register, Register),
+    LOAD16S(Register, Register),
+    STORE8(Register, Register),
+    STORE16(Register, Register),
+    MOV(Register, Register),
+    JEQ(Register, Register),
+    JNE(Register, Register),
+
+    MALLOC(Register, Register),
+    FREE(Register),
+    FTOI(Register, Register),
+    ITOF(Register, Register),
+    I32(Register, Register),
+    F32(Register, Register),
+    PRINTS(Register),
+    EXIT(Register),
+}
+
+impl CI {
+    //number of bytes the instruction takes in byte code
+    pub fn size(&self, encoding: Encoding) -> usize {
+        match (encoding, self) {
+            (Encoding::Padded, CI::LOAD(_, _)) => 8,
+            (Encoding::Padded, _) => 4,
+            (Encoding::Compact, CI::LOAD(_, _)) => 6,
+            (Encoding::Compact, CI::HLT) => 1,
+            (
+                Encoding::Compact,
+                CI::PRINT(_) | CI::PRINTS(_) | CI::INC(_) | CI::DEC(_) | CI::JMP(_) | CI::JMPB(_) | CI::JMPF(_)
+                | CI::PUSH(_) | CI::SPUSH(_) | CI::POP(_) | CI::SPOP(_) | CI::FREE(_) | CI::EXIT(_),
+            ) => 2,
+            (
+                Encoding::Compact,
+                CI::ADD(..) | CI::SUB(..) | CI::MUL(..) | CI::DIV(..) | CI::MOD(..)
+                | CI::FADD(..) | CI::FSUB(..) | CI::FMUL(..) | CI::FDIV(..)
+                | CI::EQ(..) | CI::NE(..) | CI::GT(..) | CI::LT(..) | CI::GTQ(..) | CI::LTQ(..)
+                | CI::FEQ(..) | CI::FNE(..) | CI::FGT(..) | CI::FLT(..) | CI::FGTQ(..) | CI::FLTQ(..),
+            ) => 4,
+            (Encoding::Compact, _) => 3,
+        }
+    }
+}
+
+impl CI {
+    //the instruction that bytes start with, if they start with a whole valid one
+    pub fn decode(bytes: &[u8], encoding: Encoding) -> Option<CI> {
+        let opcode = Opcode::from_u8(*bytes.first()?)?;
+        let register = |index: usize| bytes.get(index).copied().filter(|&register| (register as usize) < REGISTER_COUNT);
+        let r1 = register(1);
+        let r2 = register(2);
+        let r3 = register(3);
+        let instruction = match opcode {
+            Opcode::LOAD => CI::LOAD(r1?, memory::read_le(bytes.get(2..6)?)),
+            Opcode::PRINT => CI::PRINT(r1?),
+            Opcode::INC => CI::INC(r1?),
+            Opcode::DEC => CI::DEC(r1?),
+            Opcode::ADD => CI::ADD(r1?, r2?, r3?),
+            Opcode::SUB => CI::SUB(r1?, r2?, r3?),
+            Opcode::MUL => CI::MUL(r1?, r2?, r3?),
+            Opcode::DIV => CI::DIV(r1?, r2?, r3?),
+            Opcode::MOD => CI::MOD(r1?, r2?, r3?),
+            Opcode::FADD => CI::FADD(r1?, r2?, r3?),
+            Opcode::FSUB => CI::FSUB(r1?, r2?, r3?),
+            Opcode::FMUL => CI::FMUL(r1?, r2?, r3?),
+            Opcode::FDIV => CI::FDIV(r1?, r2?, r3?),
+            Opcode::HLT => CI::HLT,
+            Opcode::JMP => CI::JMP(r1?),
+            Opcode::JMPF => CI::JMPF(r1?),
+            Opcode::JMPB => CI::JMPB(r1?),
+            Opcode::EQ => CI::EQ(r1?, r2?, r3?),
+            Opcode::NE => CI::NE(r1?, r2?, r3?),
+            Opcode::GT => CI::GT(r1?, r2?, r3?),
+            Opcode::LT => CI::LT(r1?, r2?, r3?),
+            Opcode::GTQ => CI::GTQ(r1?, r2?, r3?),
+            Opcode::LTQ => CI::LTQ(r1?, r2?, r3?),
+            Opcode::FEQ => CI::FEQ(r1?, r2?, r3?),
+            Opcode::FNE => CI::FNE(r1?, r2?, r3?),
+            Opcode::FGT => CI::FGT(r1?, r2?, r3?),
+            Opcode::FLT => CI::FLT(r1?, r2?, r3?),
+            Opcode::FGTQ => CI::FGTQ(r1?, r2?, r3?),
+            Opcode::FLTQ => CI::FLTQ(r1?, r2?, r3?),
+            Opcode::JEQ => CI::JEQ(r1?, r2?),
+            Opcode::JNE => CI::JNE(r1?, r2?),
+            Opcode::MOV => CI::MOV(r1?, r2?),
+            Opcode::PUSH => CI::PUSH(r1?),
+            Opcode::SPUSH => CI::SPUSH(r1?),
+            Opcode::POP => CI::POP(r1?),
+            Opcode::SPOP => CI::SPOP(r1?),
+            Opcode::READ => CI::READ(r1?, r2?),
+            Opcode::WRITE => CI::WRITE(r1?, r2?),
+            Opcode::MALLOC => CI::MALLOC(r1?, r2?),
+            Opcode::FREE => CI::FREE(r1?),
+            Opcode::ITOF => CI::ITOF(r1?, r2?),
+            Opcode::FTOI => CI::FTOI(r1?, r2?),
+            Opcode::I32 => CI::I32(r1?, r2?),
+            Opcode::F32 => CI::F32(r1?, r2?),
+            Opcode::SREAD => CI::SREAD(r1?, r2?),
+            Opcode::SWRITE => CI::SWRITE(r1?, r2?),
+            Opcode::LOAD8U => CI::LOAD8U(r1?, r2?),
+            Opcode::LOAD8S => CI::LOAD8S(r1?, r2?),
+            Opcode::LOAD16U => CI::LOAD16U(r1?, r2?),
+            Opcode::LOAD16S => CI::LOAD16S(r1?, r2?),
+            Opcode::STORE8 => CI::STORE8(r1?, r2?),
+            Opcode::STORE16 => CI::STORE16(r1?, r2?),
+            Opcode::PRINTS => CI::PRINTS(r1?),
+            Opcode::EXIT => CI::EXIT(r1?),
+        };
+        //a padded instruction cut short at the end of the code isn't a whole one
+        if bytes.len() < instruction.size(encoding) {
+            return None;
+        }
+        Some(instruction)
+    }
+}
+
+impl CI {
+    //the name the instruction is written with in assembly
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            CI::LOAD(..) => "load",
+            CI::PRINT(_) => "print",
+            CI::INC(_) => "inc",
+            CI::DEC(_) => "dec",
+            CI::ADD(..) => "add",
+            CI::SUB(..) => "sub",
+            CI::MUL(..) => "mul",
+            CI::DIV(..) => "div",
+            CI::MOD(..) => "mod",
+            CI::FADD(..) => "fadd",
+            CI::FSUB(..) => "fsub",
+            CI::FMUL(..) => "fmul",
+            CI::FDIV(..) => "fdiv",
+            CI::HLT => "hlt",
+            CI::JMP(_) => "jmp",
+            CI::JMPB(_) => "jmpb",
+            CI::JMPF(_) => "jmpf",
+            CI::EQ(..) => "eq",
+            CI::NE(..) => "ne",
+            CI::GT(..) => "gt",
+            CI::LT(..) => "lt",
+            CI::GTQ(..) => "gtq",
+            CI::LTQ(..) => "ltq",
+            CI::FEQ(..) => "feq",
+            CI::FNE(..) => "fne",
+            CI::FGT(..) => "fgt",
+            CI::FLT(..) => "flt",
+            CI::FGTQ(..) => "fgtq",
+            CI::FLTQ(..) => "fltq",
+            CI::PUSH(_) => "push",
+            CI::SPUSH(_) => "spush",
+            CI::POP(_) => "pop",
+            CI::SPOP(_) => "spop",
+            CI::SREAD(..) => "sread",
+            CI::SWRITE(..) => "swrite",
+            CI::READ(..) => "read",
+            CI::WRITE(..) => "write",
+            CI::LOAD8U(..) => "load8u",
+            CI::LOAD8S(..) => "load8s",
+            CI::LOAD16U(..) => "load16u",
+            CI::LOAD16S(..) => "load16s",
+            CI::STORE8(..) => "store8",
+            CI::STORE16(..) => "store16",
+            CI::MOV(..) => "mov",
+            CI::JEQ(..) => "jeq",
+            CI::JNE(..) => "jne",
+            CI::MALLOC(..) => "malloc",
+            CI::FREE(_) => "free",
+            CI::FTOI(..) => "ftoi",
+            CI::ITOF(..) => "itof",
+            CI::I32(..) => "i32",
+            CI::F32(..) => "f32",
+            CI::PRINTS(_) => "prints",
+            CI::EXIT(_) => "exit",
+        }
+    }
+
+    //the register operands in the order they're written
+    pub fn registers(&self) -> Vec<Register> {
+        match *self {
+            CI::HLT => vec![],
+            CI::LOAD(register, _) | CI::PRINT(register) | CI::PRINTS(register) | CI::INC(register) | CI::DEC(register)
+            | CI::JMP(register) | CI::JMPB(register) | CI::JMPF(register) | CI::PUSH(register) | CI::SPUSH(register)
+            | CI::POP(register) | CI::SPOP(register) | CI::FREE(register) | CI::EXIT(register) => vec![register],
+            CI::ADD(first, second, out) | CI::SUB(first, second, out) | CI::MUL(first, second, out)
+            | CI::DIV(first, second, out) | CI::MOD(first, second, out)
+            | CI::FADD(first, second, out) | CI::FSUB(first, second, out) | CI::FMUL(first, second, out)
+            | CI::FDIV(first, second, out) | CI::EQ(first, second, out) | CI::NE(first, second, out)
+            | CI::GT(first, second, out) | CI::LT(first, second, out) | CI::GTQ(first, second, out)
+            | CI::LTQ(first, second, out) | CI::FEQ(first, second, out) | CI::FNE(first, second, out)
+            | CI::FGT(first, second, out) | CI::FLT(first, second, out) | CI::FGTQ(first, second, out)
+            | CI::FLTQ(first, second, out) => vec![first, second, out],
+            CI::SREAD(first, second) | CI::SWRITE(first, second) | CI::READ(first, second) | CI::WRITE(first, second)
+            | CI::LOAD8U(first, second) | CI::LOAD8S(first, second) | CI::LOAD16U(first, second)
+            | CI::LOAD16S(first, second) | CI::STORE8(first, second) | CI::STORE16(first, second)
+            | CI::MOV(first, second) | CI::JEQ(first, second) | CI::JNE(first, second) | CI::MALLOC(first, second)
+            | CI::FTOI(first, second) | CI::ITOF(first, second) | CI::I32(first, second) | CI::F32(first, second) => {
+                vec![first, second]
+            }
+        }
+    }
+
+    //the register the instruction sets, if any
+    pub fn writes(&self) -> Option<Register> {
+        match *self {
+            CI::LOAD(register, _) | CI::INC(register) | CI::DEC(register) | CI::POP(register) => Some(register),
+            CI::HLT | CI::PRINT(_) | CI::PRINTS(_) | CI::JMP(_) | CI::JMPB(_) | CI::JMPF(_) | CI::PUSH(_)
+            | CI::SPUSH(_) | CI::SPOP(_) | CI::FREE(_) | CI::EXIT(_)
+            | CI::SWRITE(..) | CI::WRITE(..) | CI::STORE8(..) | CI::STORE16(..) | CI::JEQ(..) | CI::JNE(..) => None,
+            //everything else writes its last operand
+            _ => self.registers().last().copied(),
+        }
+    }
+
+    //the registers whose values the instruction uses
+    pub fn reads(&self) -> Vec<Register> {
+        match *self {
+            CI::LOAD(..) | CI::POP(_) => vec![],
+            CI::INC(register) | CI::DEC(register) => vec![register],
+            _ => {
+                let mut registers = self.registers();
+                if self.writes().is_some() {
+                    registers.pop();
+                }
+                registers
+            }
+        }
+    }
+}
+
+//an instruction the way it's written in assembly, like add r1 r2 r3 or load r0 65
+impl fmt::Display for CI {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        for register in self.registers() {
+            write!(f, " r{}", register)?;
+        }
+        if let CI::LOAD(_, number) = self {
+            write!(f, " {}", number)?;
+        }
+        Ok(())
+    }
+}
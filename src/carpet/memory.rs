@@ -0,0 +1,55 @@
+use crate::carpet::cvm::{Trap, STACK_SIZE};
+use crate::carpet::cvm_heap::HEAP_SIZE;
+
+//The CVM address space, counted in bytes:
+//
+//  0x00000 - 0x00fff  null page, every access traps
+//  0x01000 - 0x013ff  stack
+//  0x01400 - 0x0ffff  static data, the program's data is mapped here at load time
+//  0x10000 - 0x4ffff  heap
+//
+//Pointers returned by MALLOC and addresses of stack slots can both be
+//passed to loads and stores as they are. Values wider than a byte are
+//stored little endian and may be unaligned, but an access must not run
+//past the end of the region it starts in.
+pub const NULL_PAGE_SIZE: usize = 0x1000;
+pub const WORD_BYTES: usize = 4;
+pub const STACK_BASE: usize = NULL_PAGE_SIZE;
+pub const STACK_BYTES: usize = STACK_SIZE * WORD_BYTES;
+pub const DATA_BASE: usize = STACK_BASE + STACK_BYTES;
+pub const HEAP_BASE: usize = 0x10000;
+pub const DATA_SIZE: usize = HEAP_BASE - DATA_BASE;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Stack(usize),
+    Data(usize),
+    Heap(usize),
+}
+
+//splits an address into the region it falls in and the offset inside that region
+pub fn resolve(address: usize) -> Result<Region, Trap> {
+    if address < NULL_PAGE_SIZE {
+        Err(Trap::NullPointer(address))
+    } else if address < DATA_BASE {
+        Ok(Region::Stack(address - STACK_BASE))
+    } else if address < HEAP_BASE {
+        Ok(Region::Data(address - DATA_BASE))
+    } else if address < HEAP_BASE + HEAP_SIZE {
+        Ok(Region::Heap(address - HEAP_BASE))
+    } else {
+        Err(Trap::Unmapped(address))
+    }
+}
+
+pub fn read_le(bytes: &[u8]) -> u32 {
+    bytes.iter().rev().fold(0, |value, &byte| (value << 8) | byte as u32)
+}
+
+pub fn write_le(bytes: &mut [u8], value: u32) {
+    let mut current_byte = value;
+    for byte in bytes {
+        *byte = current_byte as u8;
+        current_byte >>= 8;
+    }
+}
@@ -1,3 +1,12 @@
+pub mod binary;
+pub mod debug_info;
 pub mod instructions;
+pub mod coverage;
 pub mod cvm;
-mod cvm_heap;
\ No newline at end of file
+pub mod cvm_heap;
+pub mod memory;
+pub mod profiler;
+pub mod program;
+pub mod snapshot;
+pub mod tracer;
+pub mod undo;
\ No newline at end of file
@@ -0,0 +1,305 @@
+use crate::carpet::cvm::REGISTER_COUNT;
+use crate::carpet::debug_info::DebugInfo;
+use crate::carpet::instructions::{Encoding, CI};
+use crate::carpet::program::Program;
+use crate::carpet::tracer::Tracer;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+//how many rows each table of the report shows
+const REPORT_ROWS: usize = 10;
+
+//a straight run of instructions that was entered at start and left after end
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Block {
+    start: usize,
+    end: usize,
+    instructions: u64,
+}
+
+//a routine entered through call, recognised by a jmp straight after pushing
+//the address of the instruction that follows the jmp
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    entry: usize,
+    return_address: usize,
+}
+
+//Counts how often every instruction runs, which blocks of straight code run the
+//most and how many instructions run under each stack of calls.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    encoding: Encoding,
+    debug_info: DebugInfo,
+    //indexed by pc
+    counts: Vec<u64>,
+    instructions: Vec<Option<CI>>,
+    blocks: HashMap<Block, u64>,
+    //addresses some jump went to, a block starts there even if it's reached by falling through
+    landings: Vec<bool>,
+    block: Option<Block>,
+    current: Option<(usize, CI)>,
+    last_push: Option<u32>,
+    frames: Vec<Frame>,
+    //instructions run under every stack of frame entries
+    stacks: HashMap<Vec<usize>, u64>,
+    stack_count: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn close_block(&mut self) {
+        if let Some(block) = self.block.take() {
+            *self.blocks.entry(block).or_insert(0) += 1;
+        }
+    }
+
+    //the instructions counted for the current stack go to its total before it changes
+    fn flush_stack(&mut self) {
+        if self.stack_count > 0 {
+            let stack = self.frames.iter().map(|frame| frame.entry).collect();
+            *self.stacks.entry(stack).or_insert(0) += self.stack_count;
+            self.stack_count = 0;
+        }
+    }
+
+    fn location(&self, pc: usize) -> String {
+        match self.debug_info.lookup(pc) {
+            Some(location) => location.to_string(),
+            None => String::new(),
+        }
+    }
+
+    //a frame in the folded stacks, the file and line of the routine's first instruction
+    fn frame_name(&self, pc: usize) -> String {
+        match self.debug_info.lookup(pc) {
+            Some(location) => format!("{}:{}", location.path, location.entry.line),
+            None => format!("{:#x}", pc),
+        }
+    }
+
+    pub fn report(&mut self) -> String {
+        self.close_block();
+        let total: u64 = self.counts.iter().sum();
+        let percent = |count: u64| count as f64 * 100.0 / total.max(1) as f64;
+        let mut report = String::new();
+        let _ = writeln!(report, "profile: {} instructions", total);
+
+        let mut opcodes: HashMap<&str, u64> = HashMap::new();
+        for (count, instruction) in self.counts.iter().zip(&self.instructions) {
+            if let Some(instruction) = instruction {
+                *opcodes.entry(instruction.mnemonic()).or_insert(0) += count;
+            }
+        }
+        let mut opcodes: Vec<(&str, u64)> = opcodes.into_iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        let _ = writeln!(report, "opcodes:");
+        for (mnemonic, count) in opcodes {
+            let _ = writeln!(report, "  {:<8} {:>12} {:>6.2}%", mnemonic, count, percent(count));
+        }
+
+        let mut hottest: Vec<(usize, u64)> = self.counts.iter().copied().enumerate().filter(|(_, count)| *count > 0).collect();
+        hottest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let _ = writeln!(report, "hottest instructions:");
+        for &(pc, count) in hottest.iter().take(REPORT_ROWS) {
+            let _ = writeln!(report, "  {:#08x} {:>12} {:>6.2}%  {}", pc, count, percent(count), self.location(pc));
+        }
+
+        //blocks are ranked by the instructions they ran, their length times how often they ran
+        let mut blocks: Vec<(Block, u64)> = self.split_blocks().into_iter().collect();
+        blocks.sort_by(|a, b| (b.0.instructions * b.1).cmp(&(a.0.instructions * a.1)).then(a.0.start.cmp(&b.0.start)));
+        let _ = writeln!(report, "hottest blocks:");
+        for (block, count) in blocks.into_iter().take(REPORT_ROWS) {
+            let _ = writeln!(
+                report,
+                "  {:#08x}-{:#08x} {:>3} instructions x {:>10} {:>6.2}%  {}",
+                block.start,
+                block.end,
+                block.instructions,
+                count,
+                percent(block.instructions * count),
+                self.location(block.start)
+            );
+        }
+        report
+    }
+
+    //A block that ran before some jump to its middle was seen is split there, so
+    //every block ends up starting at the start of the code or where a jump went.
+    fn split_blocks(&self) -> HashMap<Block, u64> {
+        let mut blocks = HashMap::new();
+        for (block, count) in &self.blocks {
+            let mut part = Block { start: block.start, end: block.start, instructions: 0 };
+            let mut pc = block.start;
+            loop {
+                part.end = pc;
+                part.instructions += 1;
+                let next = match self.instructions[pc] {
+                    Some(instruction) if pc != block.end => pc + instruction.size(self.encoding),
+                    _ => break,
+                };
+                if self.landings[next] {
+                    *blocks.entry(part).or_insert(0) += count;
+                    part = Block { start: next, end: next, instructions: 0 };
+                }
+                pc = next;
+            }
+            *blocks.entry(part).or_insert(0) += count;
+        }
+        blocks
+    }
+
+    //one line per stack of calls, "main;file.cbc:12;file.cbc:30 1234", the format flamegraph.pl reads
+    pub fn folded(&mut self) -> String {
+        self.flush_stack();
+        let mut lines: Vec<String> = self.stacks.iter().map(|(stack, count)| {
+            let mut frames = vec!["main".to_string()];
+            frames.extend(stack.iter().map(|&entry| self.frame_name(entry)));
+            format!("{} {}", frames.join(";"), count)
+        }).collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
+
+impl Tracer for Profiler {
+    fn loaded(&mut self, program: &Program) {
+        *self = Profiler {
+            encoding: program.encoding,
+            debug_info: program.debug_info.clone(),
+            counts: vec![0; program.code.len()],
+            instructions: vec![None; program.code.len()],
+            landings: vec![false; program.code.len() + 1],
+            ..Profiler::default()
+        };
+    }
+
+    fn before(&mut self, pc: usize, instruction: &CI, registers: &[u32; REGISTER_COUNT]) {
+        self.counts[pc] += 1;
+        self.instructions[pc] = Some(*instruction);
+        if self.landings[pc] {
+            self.close_block();
+        }
+        match &mut self.block {
+            Some(block) => {
+                block.end = pc;
+                block.instructions += 1;
+            }
+            None => self.block = Some(Block { start: pc, end: pc, instructions: 1 }),
+        }
+        if let CI::PUSH(register) = instruction {
+            self.last_push = Some(registers[*register as usize]);
+        }
+        self.current = Some((pc, *instruction));
+        self.stack_count += 1;
+    }
+
+    fn after(&mut self, next_pc: usize, _registers: &[u32; REGISTER_COUNT]) {
+        let (pc, instruction) = match self.current.take() {
+            Some(current) => current,
+            None => return,
+        };
+        if !matches!(instruction, CI::JMP(_) | CI::JMPF(_) | CI::JMPB(_) | CI::JEQ(..) | CI::JNE(..) | CI::HLT | CI::EXIT(_)) {
+            return;
+        }
+        self.close_block();
+        let following = pc + instruction.size(self.encoding);
+        if next_pc == following {
+            return;
+        }
+        if let Some(landing) = self.landings.get_mut(next_pc) {
+            *landing = true;
+        }
+        if matches!(instruction, CI::JMP(_)) && self.last_push == Some(following as u32) {
+            self.flush_stack();
+            self.frames.push(Frame { entry: next_pc, return_address: following });
+            self.last_push = None;
+        } else if let Some(depth) = self.frames.iter().rposition(|frame| frame.return_address == next_pc) {
+            self.flush_stack();
+            self.frames.truncate(depth);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet::cvm::CVM;
+    use crate::carpet_assembler::assembler::CarpetAssembler;
+    use crate::parser::parse::Parser;
+
+    //calls work three times from a loop
+    const PROFILED: &str = "\
+        loadi r1 3\n\
+        loop: call work\n\
+        dec r1\n\
+        bne r1 r0 loop\n\
+        hlt\n\
+        work: inc r2\n\
+        ret\n";
+
+    fn program() -> Program {
+        let module = Parser::new().parse_source("profiled.cbc", PROFILED).unwrap();
+        CarpetAssembler::new().assemble(module).unwrap()
+    }
+
+    fn profile(runs: usize) -> Profiler {
+        let mut cvm = CVM::with_tracer(Profiler::new());
+        for _ in 0..runs {
+            cvm.new_program(program());
+            cvm.run().unwrap();
+        }
+        std::mem::take(cvm.tracer_mut())
+    }
+
+    #[test]
+    fn report() {
+        //call is load, push, load, jmp, bne is ne, load, jeq and ret is pop, jmp
+        assert_eq!(
+            profile(1).report(),
+            "profile: 35 instructions\n\
+             opcodes:\n\
+             \x20 load               10  28.57%\n\
+             \x20 jmp                 6  17.14%\n\
+             \x20 dec                 3   8.57%\n\
+             \x20 inc                 3   8.57%\n\
+             \x20 jeq                 3   8.57%\n\
+             \x20 ne                  3   8.57%\n\
+             \x20 pop                 3   8.57%\n\
+             \x20 push                3   8.57%\n\
+             \x20 hlt                 1   2.86%\n\
+             hottest instructions:\n\
+             \x20 0x000008            3   8.57%  profiled.cbc:2: call work\n\
+             \x20 0x000010            3   8.57%  profiled.cbc:2: call work\n\
+             \x20 0x000014            3   8.57%  profiled.cbc:2: call work\n\
+             \x20 0x00001c            3   8.57%  profiled.cbc:2: call work\n\
+             \x20 0x000020            3   8.57%  profiled.cbc:3: dec r1\n\
+             \x20 0x000024            3   8.57%  profiled.cbc:4: bne r1 r0 loop\n\
+             \x20 0x000028            3   8.57%  profiled.cbc:4: bne r1 r0 loop\n\
+             \x20 0x000030            3   8.57%  profiled.cbc:4: bne r1 r0 loop\n\
+             \x20 0x000038            3   8.57%  profiled.cbc:6: inc r2\n\
+             \x20 0x00003c            3   8.57%  profiled.cbc:7: ret\n\
+             hottest blocks:\n\
+             \x20 0x000008-0x00001c   4 instructions x          3  34.29%  profiled.cbc:2: call work\n\
+             \x20 0x000020-0x000030   4 instructions x          3  34.29%  profiled.cbc:3: dec r1\n\
+             \x20 0x000038-0x000040   3 instructions x          3  25.71%  profiled.cbc:6: inc r2\n\
+             \x20 0x000000-0x000000   1 instructions x          1   2.86%  profiled.cbc:1: loadi r1 3\n\
+             \x20 0x000034-0x000034   1 instructions x          1   2.86%  profiled.cbc:5: hlt\n"
+        );
+    }
+
+    #[test]
+    fn folded_stacks() {
+        //inc, pop and jmp of work run under its frame, three times
+        assert_eq!(profile(1).folded(), "main 26\nmain;profiled.cbc:6 9\n");
+    }
+
+    #[test]
+    fn loading_a_program_starts_over() {
+        assert_eq!(profile(2).report(), profile(1).report());
+        assert_eq!(profile(2).folded(), profile(1).folded());
+    }
+}
@@ -0,0 +1,63 @@
+use crate::carpet::binary::{self, Reader};
+use crate::carpet::debug_info::DebugInfo;
+use crate::carpet::instructions::{Encoding, CI};
+use std::error::Error;
+
+const MAGIC: &[u8] = b"CIMG";
+
+//A program as the CVM loads it: the byte code, the static data that gets
+//mapped at DATA_BASE and the debug info that maps the code to its source.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub encoding: Encoding,
+    pub code: Vec<u8>,
+    pub data: Vec<u8>,
+    pub debug_info: DebugInfo,
+}
+
+impl Program {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(self.encoding as u8);
+        binary::write_bytes(&mut out, &self.code);
+        binary::write_bytes(&mut out, &self.data);
+        self.debug_info.write(&mut out);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut reader = Reader::new(bytes, MAGIC)?;
+        let encoding = read_encoding(&mut reader)?;
+        let code = reader.bytes()?;
+        let data = reader.bytes()?;
+        //images linked before debug info existed end after the data
+        let debug_info = if reader.is_at_end() {
+            DebugInfo::default()
+        } else {
+            DebugInfo::read(&mut reader)?
+        };
+        Ok(Self { encoding, code, data, debug_info })
+    }
+
+    //Decodes the code into its instructions and their offsets. Decoding stops at the
+    //first bytes that aren't a valid instruction, where that is comes back as None.
+    pub fn instructions(&self) -> (Vec<(usize, CI)>, Option<usize>) {
+        let mut instructions = vec![];
+        let mut offset = 0;
+        while offset < self.code.len() {
+            match CI::decode(&self.code[offset..], self.encoding) {
+                Some(instruction) => {
+                    instructions.push((offset, instruction));
+                    offset += instruction.size(self.encoding);
+                }
+                None => return (instructions, Some(offset)),
+            }
+        }
+        (instructions, None)
+    }
+}
+
+pub fn read_encoding(reader: &mut Reader) -> Result<Encoding, Box<dyn Error>> {
+    let value = reader.u8()?;
+    Encoding::from_u8(value).ok_or_else(|| format!("unknown instruction encoding {}", value).into())
+}
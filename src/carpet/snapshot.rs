@@ -0,0 +1,179 @@
+use crate::carpet::binary::{self, Reader};
+use crate::carpet::cvm::{REGISTER_COUNT, STACK_SIZE};
+use crate::carpet::cvm_heap::CVMHeap;
+use crate::carpet::memory::STACK_BYTES;
+use crate::carpet::program::Program;
+use std::error::Error;
+
+const MAGIC: &[u8] = b"CSNP";
+
+//Everything a program needs to go on from where it was: the program with its static
+//data as it has changed, the registers, the offset of the next instruction, the stack
+//and the heap with its allocations. Fuel and output belong to the CVM it runs on.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub program: Program,
+    pub registers: [u32; REGISTER_COUNT],
+    pub counter: usize,
+    //all STACK_BYTES of it
+    pub stack: Vec<u8>,
+    pub stack_pointer: usize,
+    pub heap: CVMHeap,
+    pub exit_code: Option<i32>,
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        binary::write_bytes(&mut out, &self.program.to_bytes());
+        for &register in &self.registers {
+            binary::write_u32(&mut out, register);
+        }
+        binary::write_u32(&mut out, self.counter as u32);
+        binary::write_bytes(&mut out, binary::trim_zeros(&self.stack));
+        binary::write_u32(&mut out, self.stack_pointer as u32);
+        self.heap.write(&mut out);
+        match self.exit_code {
+            Some(exit_code) => {
+                out.push(1);
+                binary::write_u32(&mut out, exit_code as u32);
+            }
+            None => out.push(0),
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut reader = Reader::new(bytes, MAGIC)?;
+        let program = Program::from_bytes(&reader.bytes()?)?;
+        let mut registers = [0u32; REGISTER_COUNT];
+        for register in registers.iter_mut() {
+            *register = reader.u32()?;
+        }
+        let counter = reader.u32()? as usize;
+        let (instructions, invalid) = program.instructions();
+        let starts = instructions.iter().map(|(offset, _)| *offset).chain(invalid).any(|offset| offset == counter);
+        if !starts && counter != program.code.len() {
+            return Err(format!("no instruction starts at the counter {:#x}", counter).into());
+        }
+        let mut stack = reader.bytes()?;
+        if stack.len() > STACK_BYTES {
+            return Err(format!("a stack of {} bytes is larger than {}", stack.len(), STACK_BYTES).into());
+        }
+        stack.resize(STACK_BYTES, 0);
+        let stack_pointer = reader.u32()? as usize;
+        if stack_pointer > STACK_SIZE {
+            return Err(format!("stack pointer {} is past the {} slots of the stack", stack_pointer, STACK_SIZE).into());
+        }
+        let heap = CVMHeap::read(&mut reader)?;
+        let exit_code = match reader.u8()? {
+            0 => None,
+            _ => Some(reader.u32()? as i32),
+        };
+        Ok(Self { program, registers, counter, stack, stack_pointer, heap, exit_code })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet::cvm::{CVMError, CVM};
+    use crate::carpet::cvm_heap::Allocation;
+    use crate::carpet::memory::{DATA_BASE, WORD_BYTES};
+    use crate::carpet_assembler::assembler::CarpetAssembler;
+    use crate::parser::parse::Parser;
+
+    //counts up in static data and prints every count
+    const COUNTER: &str = "\
+        .data\n\
+        count: .word 64\n\
+        .text\n\
+        load r0 count\n\
+        loadi r2 70\n\
+        again: read r0 r1\n\
+        inc r1\n\
+        write r1 r0\n\
+        print r1\n\
+        blt r1 r2 again\n";
+
+    type State = (Result<i32, CVMError>, Vec<u8>, [u32; REGISTER_COUNT], Vec<u32>, Vec<Allocation>, String);
+
+    fn loaded(path: &str, source: &str) -> CVM {
+        let module = Parser::new().parse_source(path, source).unwrap();
+        let mut cvm = CVM::new().with_captured_output();
+        cvm.new_program(CarpetAssembler::new().assemble(module).unwrap());
+        cvm
+    }
+
+    //runs to the end, with what was printed before
+    fn finish(cvm: &mut CVM, mut output: Vec<u8>) -> State {
+        let result = cvm.run();
+        output.extend(cvm.take_output());
+        let data = cvm.read_memory(DATA_BASE, WORD_BYTES).ok();
+        let heap = format!("{} {:?}", cvm.heap().stats(), data);
+        (result, output, *cvm.registers(), cvm.stack(), cvm.heap().leaks().to_vec(), heap)
+    }
+
+    fn examples() -> Vec<(String, String)> {
+        let heap = std::fs::read_to_string("cbc/heap.cbc").unwrap();
+        vec![("cbc/heap.cbc".to_string(), heap), ("counter.cbc".to_string(), COUNTER.to_string())]
+    }
+
+    #[test]
+    fn restored_runs_end_like_uninterrupted_ones() {
+        for (path, source) in examples() {
+            let expected = finish(&mut loaded(&path, &source), vec![]);
+            for steps in [0, 1, 7, 40, 100] {
+                let mut cvm = loaded(&path, &source);
+                for _ in 0..steps {
+                    cvm.step().unwrap();
+                }
+                let bytes = cvm.snapshot().to_bytes();
+                let output = cvm.take_output();
+                //a CVM that ran something else first
+                let mut restored = loaded("other.cbc", "loadi r1 9\npush r1\nmalloc r1 r2\n");
+                restored.run().unwrap();
+                restored.take_output();
+                restored.restore(&Snapshot::from_bytes(&bytes).unwrap());
+                assert_eq!(finish(&mut restored, output), expected, "{} after {} steps", path, steps);
+            }
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        for (path, source) in examples() {
+            let mut cvm = loaded(&path, &source);
+            for _ in 0..30 {
+                cvm.step().unwrap();
+            }
+            let bytes = cvm.snapshot().to_bytes();
+            assert_eq!(Snapshot::from_bytes(&bytes).unwrap().to_bytes(), bytes, "{}", path);
+        }
+    }
+
+    #[test]
+    fn truncated_and_corrupt_snapshots_are_rejected() {
+        let mut cvm = loaded("counter.cbc", COUNTER);
+        for _ in 0..10 {
+            cvm.step().unwrap();
+        }
+        let snapshot = cvm.snapshot();
+        let bytes = snapshot.to_bytes();
+        for len in 0..bytes.len() {
+            assert!(Snapshot::from_bytes(&bytes[..len]).is_err(), "{} of {} bytes", len, bytes.len());
+        }
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert!(Snapshot::from_bytes(&magic).is_err());
+        let mut corrupt = snapshot.clone();
+        corrupt.counter = 1;
+        assert!(Snapshot::from_bytes(&corrupt.to_bytes()).is_err());
+        let mut corrupt = snapshot.clone();
+        corrupt.stack_pointer = STACK_SIZE + 1;
+        assert!(Snapshot::from_bytes(&corrupt.to_bytes()).is_err());
+        let mut corrupt = snapshot;
+        corrupt.stack = vec![1; STACK_BYTES + 4];
+        assert!(Snapshot::from_bytes(&corrupt.to_bytes()).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+use crate::carpet::cvm::{CVMError, REGISTER_COUNT};
+use crate::carpet::cvm_heap::Allocation;
+use crate::carpet::debug_info::DebugInfo;
+use crate::carpet::instructions::{Register, CI};
+use crate::carpet::program::Program;
+use std::io::{self, Write};
+
+//Hooks the CVM calls around every instruction it runs. CVM is generic over its
+//tracer, so with NoTracer the calls compile to nothing.
+pub trait Tracer {
+    //a new program was loaded, and runs from the start
+    fn loaded(&mut self, _program: &Program) {}
+    //instruction at pc is about to run
+    fn before(&mut self, _pc: usize, _instruction: &CI, _registers: &[u32; REGISTER_COUNT]) {}
+    //it ran, next_pc is where the program goes on, the end of the code once it halts
+    fn after(&mut self, _next_pc: usize, _registers: &[u32; REGISTER_COUNT]) {}
+    //it trapped instead
+    fn trapped(&mut self, _error: &CVMError) {}
+    //The instruction that is running changed memory, the stack pointer or the heap. The
+    //register it writes isn't reported, before tells which one that is and its value.
+    fn changed(&mut self, _change: Change) {}
+}
+
+//What an instruction changed, with the value it had before. Taking back the changes
+//of an instruction, the last one first, puts everything back the way it was.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    //not reported by the CVM, a tracer has it from before
+    Register(Register, u32),
+    //the len bytes at address, as a little endian value
+    Memory(usize, usize, u32),
+    StackPointer(usize),
+    //a malloc made an allocation at this offset into the heap
+    Allocated(usize),
+    //a free removed this allocation
+    Freed(Allocation),
+}
+
+#[derive(Debug)]
+pub struct NoTracer;
+
+impl Tracer for NoTracer {}
+
+//a tracer that may be switched off
+impl<T: Tracer> Tracer for Option<T> {
+    fn loaded(&mut self, program: &Program) {
+        if let Some(tracer) = self {
+            tracer.loaded(program);
+        }
+    }
+
+    fn before(&mut self, pc: usize, instruction: &CI, registers: &[u32; REGISTER_COUNT]) {
+        if let Some(tracer) = self {
+            tracer.before(pc, instruction, registers);
+        }
+    }
+
+    fn after(&mut self, next_pc: usize, registers: &[u32; REGISTER_COUNT]) {
+        if let Some(tracer) = self {
+            tracer.after(next_pc, registers);
+        }
+    }
+
+    fn trapped(&mut self, error: &CVMError) {
+        if let Some(tracer) = self {
+            tracer.trapped(error);
+        }
+    }
+
+    fn changed(&mut self, change: Change) {
+        if let Some(tracer) = self {
+            tracer.changed(change);
+        }
+    }
+}
+
+//two tracers watching the same run
+impl<A: Tracer, B: Tracer> Tracer for (A, B) {
+    fn loaded(&mut self, program: &Program) {
+        self.0.loaded(program);
+        self.1.loaded(program);
+    }
+
+    fn before(&mut self, pc: usize, instruction: &CI, registers: &[u32; REGISTER_COUNT]) {
+        self.0.before(pc, instruction, registers);
+        self.1.before(pc, instruction, registers);
+    }
+
+    fn after(&mut self, next_pc: usize, registers: &[u32; REGISTER_COUNT]) {
+        self.0.after(next_pc, registers);
+        self.1.after(next_pc, registers);
+    }
+
+    fn trapped(&mut self, error: &CVMError) {
+        self.0.trapped(error);
+        self.1.trapped(error);
+    }
+
+    fn changed(&mut self, change: Change) {
+        self.0.changed(change);
+        self.1.changed(change);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    //0x000018  add r1 r2 r3          r1=5 r2=7 -> r3=12  math.cbc:7
+    Text,
+    //{"pc":24,"location":"math.cbc:7","instruction":"add r1 r2 r3","reads":{"r1":5,"r2":7},"writes":{"r3":12}}
+    JsonLines,
+}
+
+//an instruction that ran, logged once it's known what it wrote
+struct Step {
+    pc: usize,
+    instruction: CI,
+    reads: Vec<(Register, u32)>,
+}
+
+//Writes a line for every instruction the CVM runs with the file and line it came from
+//and the values of the registers it read and wrote. Write errors are kept until finish,
+//so tracing never stops a program.
+pub struct InstructionTracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    debug_info: DebugInfo,
+    step: Option<Step>,
+    error: Option<io::Error>,
+}
+
+impl InstructionTracer {
+    pub fn new(out: Box<dyn Write>, format: TraceFormat) -> Self {
+        Self { out, format, debug_info: DebugInfo::default(), step: None, error: None }
+    }
+
+    //flushes the trace and reports the first write that failed
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.out.flush()
+    }
+
+    //the file and line the instruction at pc was written on, None without debug info
+    fn location(&self, pc: usize) -> Option<String> {
+        self.debug_info.lookup(pc).map(|location| format!("{}:{}", location.path, location.entry.line))
+    }
+
+    fn write_step(&mut self, step: Step, writes: Option<(Register, u32)>, trap: Option<String>) {
+        let location = self.location(step.pc);
+        let line = match self.format {
+            TraceFormat::Text => {
+                let mut values: Vec<String> = step.reads.iter()
+                    .map(|(register, value)| format!("r{}={}", register, *value as i32))
+                    .collect();
+                if let Some((register, value)) = writes {
+                    values.push(format!("-> r{}={}", register, value as i32));
+                }
+                if let Some(trap) = &trap {
+                    values.push(format!("trapped: {}", trap));
+                }
+                if let Some(location) = &location {
+                    values.push(format!(" {}", location));
+                }
+                format!("{:#08x}  {:<22}{}", step.pc, step.instruction.to_string(), values.join(" "))
+            }
+            TraceFormat::JsonLines => {
+                let object = |values: &[(Register, u32)]| {
+                    let fields: Vec<String> = values.iter()
+                        .map(|(register, value)| format!("\"r{}\":{}", register, value))
+                        .collect();
+                    format!("{{{}}}", fields.join(","))
+                };
+                let mut line = format!("{{\"pc\":{}", step.pc);
+                if let Some(location) = &location {
+                    line.push_str(&format!(",\"location\":\"{}\"", json_escape(location)));
+                }
+                line.push_str(&format!(
+                    ",\"instruction\":\"{}\",\"reads\":{},\"writes\":{}",
+                    step.instruction,
+                    object(&step.reads),
+                    object(writes.as_slice())
+                ));
+                if let Some(trap) = &trap {
+                    line.push_str(&format!(",\"trap\":\"{}\"", json_escape(trap)));
+                }
+                line.push('}');
+                line
+            }
+        };
+        if self.error.is_none() {
+            if let Err(error) = writeln!(self.out, "{}", line) {
+                self.error = Some(error);
+            }
+        }
+    }
+}
+
+impl Tracer for InstructionTracer {
+    fn loaded(&mut self, program: &Program) {
+        self.debug_info = program.debug_info.clone();
+        self.step = None;
+    }
+
+    fn before(&mut self, pc: usize, instruction: &CI, registers: &[u32; REGISTER_COUNT]) {
+        let reads = instruction.reads().into_iter()
+            .map(|register| (register, registers[register as usize]))
+            .collect();
+        self.step = Some(Step { pc, instruction: *instruction, reads });
+    }
+
+    fn after(&mut self, _next_pc: usize, registers: &[u32; REGISTER_COUNT]) {
+        if let Some(step) = self.step.take() {
+            let writes = step.instruction.writes().map(|register| (register, registers[register as usize]));
+            self.write_step(step, writes, None);
+        }
+    }
+
+    fn trapped(&mut self, error: &CVMError) {
+        if let Some(step) = self.step.take() {
+            self.write_step(step, None, Some(error.trap.to_string()));
+        }
+    }
+}
+
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet::cvm::CVM;
+    use crate::carpet_assembler::assembler::CarpetAssembler;
+    use crate::parser::parse::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    //a trap on the last line, so the trace has every kind of line
+    const TRACED: &str = "\
+        load r1 5\n\
+        load r2 -7\n\
+        add r1 r2 r3\n\
+        push r3\n\
+        div r3 r0 r4\n";
+
+    //what the tracer wrote, kept after the tracer is gone
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(format: TraceFormat) -> String {
+        let out = Shared::default();
+        let module = Parser::new().parse_source("traced.cbc", TRACED).unwrap();
+        let mut cvm = CVM::with_tracer(InstructionTracer::new(Box::new(out.clone()), format));
+        cvm.new_program(CarpetAssembler::new().assemble(module).unwrap());
+        assert!(cvm.run().is_err());
+        cvm.tracer_mut().finish().unwrap();
+        let bytes = out.0.borrow().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn text_trace() {
+        assert_eq!(
+            trace(TraceFormat::Text),
+            "0x000000  load r1 5             -> r1=5  traced.cbc:1\n\
+             0x000008  load r2 4294967289    -> r2=-7  traced.cbc:2\n\
+             0x000010  add r1 r2 r3          r1=5 r2=-7 -> r3=-2  traced.cbc:3\n\
+             0x000014  push r3               r3=-2  traced.cbc:4\n\
+             0x000018  div r3 r0 r4          r3=-2 r0=0 trapped: division by zero  traced.cbc:5\n"
+        );
+    }
+
+    #[test]
+    fn json_trace() {
+        assert_eq!(
+            trace(TraceFormat::JsonLines),
+            "{\"pc\":0,\"location\":\"traced.cbc:1\",\"instruction\":\"load r1 5\",\"reads\":{},\"writes\":{\"r1\":5}}\n\
+             {\"pc\":8,\"location\":\"traced.cbc:2\",\"instruction\":\"load r2 4294967289\",\"reads\":{},\"writes\":{\"r2\":4294967289}}\n\
+             {\"pc\":16,\"location\":\"traced.cbc:3\",\"instruction\":\"add r1 r2 r3\",\"reads\":{\"r1\":5,\"r2\":4294967289},\"writes\":{\"r3\":4294967294}}\n\
+             {\"pc\":20,\"location\":\"traced.cbc:4\",\"instruction\":\"push r3\",\"reads\":{\"r3\":4294967294},\"writes\":{}}\n\
+             {\"pc\":24,\"location\":\"traced.cbc:5\",\"instruction\":\"div r3 r0 r4\",\"reads\":{\"r3\":4294967294,\"r0\":0},\"writes\":{},\"trap\":\"division by zero\"}\n"
+        );
+    }
+
+    #[test]
+    fn json_escapes_quotes_and_control_characters() {
+        assert_eq!(json_escape("a\"b\\c\nd"), "a\\\"b\\\\c\\u000ad");
+    }
+}
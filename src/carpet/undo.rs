@@ -0,0 +1,181 @@
+use crate::carpet::cvm::REGISTER_COUNT;
+use crate::carpet::instructions::CI;
+use crate::carpet::program::Program;
+use crate::carpet::tracer::{Change, Tracer};
+use std::collections::VecDeque;
+
+//the most instructions the log takes back, the oldest ones are forgotten after that
+const UNDO_LIMIT: usize = 1_000_000;
+
+//Records what every instruction the CVM runs changes, so CVM::step_back can take
+//the instructions back again, the last one first.
+#[derive(Debug, Default)]
+pub struct UndoLog {
+    //the pc of every instruction that ran and how many changes it made, oldest first
+    steps: VecDeque<(usize, usize)>,
+    changes: VecDeque<Change>,
+}
+
+impl UndoLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //the changes of the last instruction, in the order it made them
+    pub fn last(&self) -> Vec<Change> {
+        let count = self.steps.back().map_or(0, |&(_, count)| count);
+        self.changes.range(self.changes.len() - count..).copied().collect()
+    }
+
+    //the pc of the last instruction and its changes, in the order it made them
+    pub fn pop(&mut self) -> Option<(usize, Vec<Change>)> {
+        let (pc, count) = self.steps.pop_back()?;
+        let changes = self.changes.drain(self.changes.len() - count..).collect();
+        Some((pc, changes))
+    }
+}
+
+impl Tracer for UndoLog {
+    fn loaded(&mut self, _program: &Program) {
+        self.steps.clear();
+        self.changes.clear();
+    }
+
+    fn before(&mut self, pc: usize, instruction: &CI, registers: &[u32; REGISTER_COUNT]) {
+        if self.steps.len() == UNDO_LIMIT {
+            if let Some((_, count)) = self.steps.pop_front() {
+                self.changes.drain(..count);
+            }
+        }
+        self.steps.push_back((pc, 0));
+        if let Some(register) = instruction.writes() {
+            self.changed(Change::Register(register, registers[register as usize]));
+        }
+    }
+
+    fn changed(&mut self, change: Change) {
+        if let Some((_, count)) = self.steps.back_mut() {
+            self.changes.push_back(change);
+            *count += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet::cvm::{CVM, Trap};
+    use crate::carpet::cvm_heap::Allocation;
+    use crate::carpet::memory::{DATA_BASE, HEAP_BASE};
+    use crate::carpet_assembler::assembler::CarpetAssembler;
+    use crate::parser::parse::Parser;
+
+    const HEAP_BYTES: usize = 64;
+
+    //allocates, writes and frees, then frees twice and traps
+    const FREES: &str = "\
+        .data\n\
+        byte: .word 0\n\
+        .text\n\
+        loadi r1 8\n\
+        malloc r1 r2\n\
+        loadi r3 -7\n\
+        write r3 r2\n\
+        malloc r1 r4\n\
+        free r2\n\
+        push r3\n\
+        spush r1\n\
+        load r5 byte\n\
+        store8 r3 r5\n\
+        spop r1\n\
+        pop r6\n\
+        malloc r1 r2\n\
+        free r4\n\
+        free r4\n";
+
+    //everything the program can see or the debugger shows, but the peak of the heap,
+    //which stays where it got to
+    #[derive(Debug, PartialEq)]
+    struct State {
+        next: Option<usize>,
+        registers: [u32; REGISTER_COUNT],
+        stack: Vec<u32>,
+        allocations: Vec<Allocation>,
+        counts: (usize, usize, usize),
+        heap: Vec<u8>,
+        data: Option<Vec<u8>>,
+        exit_code: Option<i32>,
+    }
+
+    fn state(cvm: &mut CVM<UndoLog>) -> State {
+        let stats = cvm.heap().stats();
+        State {
+            next: cvm.next_instruction().map(|(pc, _)| pc),
+            registers: *cvm.registers(),
+            stack: cvm.stack(),
+            allocations: cvm.heap().leaks().to_vec(),
+            counts: (stats.bytes_in_use, stats.total_allocations, stats.total_frees),
+            heap: cvm.read_memory(HEAP_BASE, HEAP_BYTES).unwrap(),
+            data: cvm.read_memory(DATA_BASE, 4).ok(),
+            exit_code: cvm.exit_code(),
+        }
+    }
+
+    fn loaded(path: &str, source: &str) -> CVM<UndoLog> {
+        let module = Parser::new().parse_source(path, source).unwrap();
+        let mut cvm = CVM::with_tracer(UndoLog::new()).with_heap_size(HEAP_BYTES).with_captured_output();
+        cvm.new_program(CarpetAssembler::new().assemble(module).unwrap());
+        cvm
+    }
+
+    //steps to the end or the trap, then back to the start, and returns the trap
+    fn there_and_back(path: &str, source: &str) -> Option<Trap> {
+        let mut cvm = loaded(path, source);
+        let mut states = vec![state(&mut cvm)];
+        let mut trap = None;
+        while cvm.next_instruction().is_some() {
+            match cvm.step() {
+                Ok(_) => states.push(state(&mut cvm)),
+                Err(error) => {
+                    trap = Some(error.trap);
+                    break;
+                }
+            }
+        }
+        //the instruction that trapped is taken back like one that ran, what it did before
+        //the trap is put back
+        if trap.is_some() {
+            assert!(cvm.step_back());
+        }
+        let mut back = 0;
+        while let Some(expected) = states.pop() {
+            assert_eq!(state(&mut cvm), expected, "{} {} instructions back", path, back);
+            if !states.is_empty() {
+                assert!(cvm.step_back());
+                back += 1;
+            }
+        }
+        assert!(!cvm.step_back());
+        trap
+    }
+
+    #[test]
+    fn stepping_back_restores_everything() {
+        let heap = std::fs::read_to_string("cbc/heap.cbc").unwrap();
+        assert_eq!(there_and_back("cbc/heap.cbc", &heap), Some(Trap::OutOfHeap(90)));
+        assert_eq!(there_and_back("frees.cbc", FREES), Some(Trap::InvalidFree(HEAP_BASE + 8)));
+        assert_eq!(there_and_back("exit.cbc", "loadi r1 3\npush r1\nexit r1\n"), None);
+    }
+
+    #[test]
+    fn the_log_forgets_a_program_that_is_loaded_over() {
+        let mut cvm = loaded("frees.cbc", FREES);
+        assert!(cvm.run().is_err());
+        let module = Parser::new().parse_source("next.cbc", "loadi r1 1\n").unwrap();
+        cvm.new_program(CarpetAssembler::new().assemble(module).unwrap());
+        cvm.run().unwrap();
+        assert!(cvm.step_back());
+        assert_eq!(cvm.registers()[1], 0);
+        assert!(!cvm.step_back());
+    }
+}
@@ -1,73 +1,258 @@
-use crate::carpet::instructions::Opcode;
+use crate::carpet::debug_info::DebugInfo;
+use crate::carpet::instructions::{Encoding, Opcode, CI};
+use crate::carpet::memory::DATA_BASE;
+use crate::carpet::program::Program;
+use crate::carpet_assembler::expression::{Atom, Expression, Value};
+use crate::carpet_assembler::linker::Linker;
+use crate::carpet_assembler::object::{Object, ObjectSymbol, Relocation};
+use std::collections::HashMap;
+use std::error::Error;
 
-type Register = u8;
+//where the 32 bit number of a LOAD starts, counted from its first byte
+const LOAD_NUMBER_OFFSET: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Section {
+    Code,
+    Data,
+}
 
 #[derive(Debug, Clone, Copy)]
-pub enum CI {
-    LOAD(Register, u32),
-    PRINT(Register),
-    INC(Register),
-    DEC(Register),
-    ADD(Register, Register, Register),
-    SUB(Register, Register, Register),
-    MUL(Register, Register, Register),
-    DIV(Register, Register, Register),
-    MOD(Register, Register, Register),
-    FADD(Register, Register, Register),
-    FSUB(Register, Register, Register),
-    FMUL(Register, Register, Register),
-    FDIV(Register, Register, Register),
-    HLT,
-    JMP(Register),
-    JMPB(Register),
-    JMPF(Register),
-    EQ(Register, Register, Register),
-    NE(Register, Register, Register),
-    GT(Register, Register, Register),
-    LT(Register, Register, Register),
-    GTQ(Register, Register, Register),
-    LTQ(Register, Register, Register),
-    FEQ(Register, Register, Register),
-    FNE(Register, Register, Register),
-    FGT(Register, Register, Register),
-    FLT(Register, Register, Register),
-    FGTQ(Register, Register, Register),
-    FLTQ(Register, Register, Register),
-    PUSH(Register),
-    SPUSH(Register),
-    POP(Register),
-    SPOP(Register),
-    SREAD(Register, Register),
-    SWRITE(Register, Register),
-    MOV(Register, Register),
-    JEQ(Register, Register),
-    JNE(Register, Register),
-
-    MALLOC(Register, Register),
-    FREE(Register),
-    FTOI(Register, Register),
-    ITOF(Register, Register),
-    I32(Register, Register),
-    F32(Register, Register),
+pub struct Symbol {
+    pub section: Section,
+    //index into Module::code for code symbols, byte offset into Module::data for data symbols
+    pub offset: usize,
+    pub exported: bool,
 }
 
-pub struct CarpetAssembler {}
+//a LOAD whose value is an expression using labels, filled in once the code is laid out
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub at: usize,
+    pub expression: Expression,
+}
+
+//the line of source an instruction or a piece of data was written on
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    pub path: String,
+    pub line: usize,
+    //where the instruction or directive starts, after any label
+    pub column: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Module {
+    pub code: Vec<CI>,
+    pub data: Vec<u8>,
+    pub symbols: HashMap<String, Symbol>,
+    pub references: Vec<Reference>,
+    //the source line of every instruction in code
+    pub code_lines: Vec<SourceLine>,
+    //the byte offset into data where each line's data starts, in order
+    pub data_lines: Vec<(usize, SourceLine)>,
+}
+
+//a row of a listing, bytes longer than LISTING_BYTES continue on the rows below
+struct ListingRow<'a> {
+    address: usize,
+    bytes: Vec<u8>,
+    source: &'a SourceLine,
+    labels: Vec<String>,
+}
+
+const LISTING_BYTES: usize = 8;
+
+pub struct CarpetAssembler {
+    encoding: Encoding,
+}
 
 impl CarpetAssembler {
     pub fn new() -> Self {
-        Self {}
+        Self { encoding: Encoding::Padded }
+    }
+
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    //assembles a module that is a whole program on its own
+    pub fn assemble(&self, module: Module) -> Result<Program, Box<dyn Error>> {
+        Linker::new().link(&[self.assemble_object(module)?])
+    }
+
+    pub fn assemble_object(&self, module: Module) -> Result<Object, Box<dyn Error>> {
+        let offsets = self.layout(&module.code);
+        let (code, relocations) = self.resolve_references(&module, &offsets)?;
+        let mut symbols: Vec<ObjectSymbol> = module.symbols.into_iter().map(|(name, symbol)| ObjectSymbol {
+            name,
+            section: symbol.section,
+            offset: match symbol.section {
+                Section::Code => offsets[symbol.offset],
+                Section::Data => symbol.offset,
+            },
+            exported: symbol.exported,
+        }).collect();
+        //the symbols come out of a HashMap, sorted the same source gives the same object
+        symbols.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut debug_info = DebugInfo::default();
+        for (offset, source) in offsets.iter().zip(&module.code_lines) {
+            debug_info.add(*offset, &source.path, source.line, source.column, &source.text);
+        }
+        Ok(Object {
+            encoding: self.encoding,
+            code: self.generate_byte_code(code),
+            data: module.data,
+            symbols,
+            relocations,
+            debug_info,
+        })
+    }
+
+    //Sets every reference whose value is known once the module is laid out. The ones
+    //that depend on where the linker places the module become relocations, which works
+    //as long as their value is a single label plus a constant.
+    fn resolve_references(&self, module: &Module, offsets: &[usize]) -> Result<(Vec<CI>, Vec<Relocation>), Box<dyn Error>> {
+        let offset_of = |symbol: &Symbol| match symbol.section {
+            Section::Code => offsets[symbol.offset],
+            Section::Data => symbol.offset,
+        };
+        let mut code = module.code.clone();
+        let mut relocations = vec![];
+        for reference in &module.references {
+            let source = &module.code_lines[reference.at];
+            let located = |error: String| format!("{}:{}: {}", source.path, source.line, error);
+            let value = reference.expression.evaluate(&|name| match module.symbols.get(name) {
+                Some(symbol) => Value::relative(Atom::Section(symbol.section), offset_of(symbol) as u32),
+                None => Value::relative(Atom::Symbol(name.to_string()), 0),
+            }).map_err(|error| located(error.to_string()))?;
+            let (symbol, addend) = match value.terms.as_slice() {
+                [] => {
+                    if let CI::LOAD(register, _) = code[reference.at] {
+                        code[reference.at] = CI::LOAD(register, value.constant);
+                    }
+                    continue;
+                }
+                [(Atom::Symbol(name), 1)] => (name.clone(), value.constant),
+                //any label of the section will do, the addend makes up for where it is
+                [(Atom::Section(section), 1)] => {
+                    let name = reference.expression.symbols().into_iter()
+                        .find(|name| module.symbols.get(*name).is_some_and(|symbol| symbol.section == *section))
+                        .unwrap_or_default();
+                    let addend = value.constant.wrapping_sub(offset_of(&module.symbols[name]) as u32);
+                    (name.to_string(), addend)
+                }
+                _ => return Err(located(format!("{} isn't a constant or a label plus a constant", source.text)).into()),
+            };
+            relocations.push(Relocation { offset: offsets[reference.at] + LOAD_NUMBER_OFFSET, symbol, addend });
+        }
+        Ok((code, relocations))
+    }
+
+    //Every instruction and every line of data with its address, its bytes, the line it
+    //was written on and the labels defined or used there. Addresses are the ones the
+    //module gets when it is linked on its own, imported labels are shown as extern.
+    pub fn listing(&self, module: &Module) -> Result<String, Box<dyn Error>> {
+        let offsets = self.layout(&module.code);
+        let (code, _) = self.resolve_references(module, &offsets)?;
+        let address = |symbol: &Symbol| match symbol.section {
+            Section::Code => offsets[symbol.offset],
+            Section::Data => DATA_BASE + symbol.offset,
+        };
+        let mut defined: HashMap<(Section, usize), Vec<String>> = HashMap::new();
+        for (name, symbol) in &module.symbols {
+            let label = format!("{} = {:#06x}", display_name(name), address(symbol));
+            defined.entry((symbol.section, symbol.offset)).or_default().push(label);
+        }
+        for labels in defined.values_mut() {
+            labels.sort();
+        }
+        let mut code_rows = vec![];
+        for (index, (instruction, source)) in code.iter().zip(&module.code_lines).enumerate() {
+            let mut labels = defined.remove(&(Section::Code, index)).unwrap_or_default();
+            let references = module.references.iter().filter(|reference| reference.at == index);
+            for name in references.flat_map(|reference| reference.expression.symbols()) {
+                let value = match module.symbols.get(name) {
+                    Some(symbol) => format!("{:#06x}", address(symbol)),
+                    None => "extern".to_string(),
+                };
+                labels.push(format!("{} = {}", display_name(name), value));
+            }
+            code_rows.push(ListingRow {
+                address: offsets[index],
+                bytes: self.generate_byte_code(vec![*instruction]),
+                source,
+                labels,
+            });
+        }
+        let mut data_rows = vec![];
+        for (index, (start, source)) in module.data_lines.iter().enumerate() {
+            let end = module.data_lines.get(index + 1).map_or(module.data.len(), |(next, _)| *next);
+            data_rows.push(ListingRow {
+                address: DATA_BASE + start,
+                bytes: module.data[*start..end].to_vec(),
+                source,
+                labels: defined.remove(&(Section::Data, *start)).unwrap_or_default(),
+            });
+        }
+        let rows = || code_rows.iter().chain(&data_rows);
+        let location_width = rows().map(|row| format!("{}:{}", row.source.path, row.source.line).len()).max().unwrap_or(0);
+        let text_width = rows().map(|row| row.source.text.len()).max().unwrap_or(0);
+        let mut listing = String::new();
+        for (section, rows) in [(".text", &code_rows), (".data", &data_rows)] {
+            if rows.is_empty() {
+                continue;
+            }
+            listing.push_str(section);
+            listing.push('\n');
+            for row in rows {
+                let location = format!("{}:{}", row.source.path, row.source.line);
+                let mut chunks = row.bytes.chunks(LISTING_BYTES);
+                let line = format!(
+                    "{:06x}  {:<bytes_width$}  {:<location_width$}  {:<text_width$}  {}",
+                    row.address,
+                    hex(chunks.next().unwrap_or_default()),
+                    location,
+                    row.source.text,
+                    row.labels.join(", "),
+                    bytes_width = LISTING_BYTES * 3 - 1,
+                );
+                listing.push_str(line.trim_end());
+                listing.push('\n');
+                for (index, chunk) in chunks.enumerate() {
+                    let address = row.address + (index + 1) * LISTING_BYTES;
+                    listing.push_str(&format!("{:06x}  {}\n", address, hex(chunk)));
+                }
+            }
+        }
+        Ok(listing)
+    }
+
+    //byte offset of every instruction, followed by the offset of the end of the code
+    fn layout(&self, instructions: &[CI]) -> Vec<usize> {
+        let mut offsets = Vec::with_capacity(instructions.len() + 1);
+        let mut offset = 0;
+        for instruction in instructions {
+            offsets.push(offset);
+            offset += instruction.size(self.encoding);
+        }
+        offsets.push(offset);
+        offsets
     }
 
     pub fn generate_byte_code(&self, instructions: Vec<CI>) -> Vec<u8> {
         let mut carpet_byte_code = Vec::with_capacity(instructions.len());
         for instruction in instructions {
+            let start = carpet_byte_code.len();
             match instruction {
                 CI::LOAD(register, number) => {
                     let mut bytes = [0u8; 4];
                     let mut current_byte = number;
                     for byte in &mut bytes {
                         *byte = current_byte as u8;
-                        current_byte = current_byte >> 8;
+                        current_byte >>= 8;
                     }
                     carpet_byte_code.extend(
                         &[
@@ -185,11 +370,35 @@ impl CarpetAssembler {
                     carpet_byte_code.extend(&[Opcode::SPOP as u8, register, 0, 0]);
                 }
                 CI::SREAD(register0, register1) => {
-                    carpet_byte_code.extend(&[Opcode::READ as u8, register0, register1, 0]);
+                    carpet_byte_code.extend(&[Opcode::SREAD as u8, register0, register1, 0]);
                 }
                 CI::SWRITE(register0, register1) => {
+                    carpet_byte_code.extend(&[Opcode::SWRITE as u8, register0, register1, 0]);
+                }
+                CI::READ(register0, register1) => {
+                    carpet_byte_code.extend(&[Opcode::READ as u8, register0, register1, 0]);
+                }
+                CI::WRITE(register0, register1) => {
                     carpet_byte_code.extend(&[Opcode::WRITE as u8, register0, register1, 0]);
                 }
+                CI::LOAD8U(register0, register1) => {
+                    carpet_byte_code.extend(&[Opcode::LOAD8U as u8, register0, register1, 0]);
+                }
+                CI::LOAD8S(register0, register1) => {
+                    carpet_byte_code.extend(&[Opcode::LOAD8S as u8, register0, register1, 0]);
+                }
+                CI::LOAD16U(register0, register1) => {
+                    carpet_byte_code.extend(&[Opcode::LOAD16U as u8, register0, register1, 0]);
+                }
+                CI::LOAD16S(register0, register1) => {
+                    carpet_byte_code.extend(&[Opcode::LOAD16S as u8, register0, register1, 0]);
+                }
+                CI::STORE8(register0, register1) => {
+                    carpet_byte_code.extend(&[Opcode::STORE8 as u8, register0, register1, 0]);
+                }
+                CI::STORE16(register0, register1) => {
+                    carpet_byte_code.extend(&[Opcode::STORE16 as u8, register0, register1, 0]);
+                }
                 CI::MOV(register0, register1) => {
                     carpet_byte_code.extend(&[Opcode::MOV as u8, register0, register1, 0]);
                 }
@@ -211,8 +420,78 @@ impl CarpetAssembler {
                 CI::F32(register0, register1) => {
                     carpet_byte_code.extend(&[Opcode::F32 as u8, register0, register1, 0]);
                 }
+                CI::PRINTS(register0) => {
+                    carpet_byte_code.extend(&[Opcode::PRINTS as u8, register0, 0, 0]);
+                }
+                CI::EXIT(register0) => {
+                    carpet_byte_code.extend(&[Opcode::EXIT as u8, register0, 0, 0]);
+                }
             }
+            //the padding is always at the end, compact instructions just leave it out
+            carpet_byte_code.truncate(start + instruction.size(self.encoding));
         }
         carpet_byte_code
     }
-}
\ No newline at end of file
+}
+
+//labels that aren't exported carry the file they are in as name%file
+pub fn display_name(symbol: &str) -> &str {
+    symbol.split('%').next().unwrap_or(symbol)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet::cvm::REGISTER_COUNT;
+
+    //one instruction of every opcode, with registers and numbers that aren't 0
+    fn every_instruction() -> Vec<CI> {
+        let last = (REGISTER_COUNT - 1) as u8;
+        let mut instructions: Vec<CI> = (0..=u8::MAX)
+            .filter(|&opcode| Opcode::from_u8(opcode).is_some())
+            .map(|opcode| CI::decode(&[opcode, 1, last, 3, 0xff, 0x80, 0, 0], Encoding::Padded).unwrap())
+            .collect();
+        instructions.push(CI::LOAD(last, u32::MAX));
+        instructions.push(CI::LOAD(0, 0));
+        instructions
+    }
+
+    fn decode_all(code: &[u8], encoding: Encoding) -> Vec<CI> {
+        let mut instructions = vec![];
+        let mut offset = 0;
+        while offset < code.len() {
+            let instruction = CI::decode(&code[offset..], encoding).unwrap();
+            offset += instruction.size(encoding);
+            instructions.push(instruction);
+        }
+        instructions
+    }
+
+    #[test]
+    fn encoded_instructions_decode_to_themselves() {
+        let instructions = every_instruction();
+        let expected = format!("{:?}", instructions);
+        for encoding in [Encoding::Padded, Encoding::Compact] {
+            let code = CarpetAssembler::new().with_encoding(encoding).generate_byte_code(instructions.clone());
+            let size: usize = instructions.iter().map(|instruction| instruction.size(encoding)).sum();
+            assert_eq!(code.len(), size);
+            assert_eq!(format!("{:?}", decode_all(&code, encoding)), expected, "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn compact_only_leaves_out_the_padding() {
+        for instruction in every_instruction() {
+            let padded = CarpetAssembler::new().generate_byte_code(vec![instruction]);
+            let compact = CarpetAssembler::new().with_encoding(Encoding::Compact).generate_byte_code(vec![instruction]);
+            assert_eq!(&padded[..compact.len()], &compact[..], "{}", instruction);
+            assert!(padded[compact.len()..].iter().all(|&byte| byte == 0), "{}", instruction);
+            //cut short, it is no instruction at all
+            assert!(CI::decode(&compact[..compact.len() - 1], Encoding::Compact).is_none(), "{}", instruction);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use crate::carpet::memory::DATA_BASE;
+use crate::carpet::program::Program;
+use crate::carpet_assembler::assembler::{display_name, Section};
+use crate::carpet_assembler::object::Object;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+//bytes shown on one row of the data and of code that doesn't decode
+const ROW_BYTES: usize = 16;
+
+//Turns byte code back into assembly, one instruction per row with its offset and
+//the source line it was assembled from when the debug info has it.
+pub struct Disassembler {
+    //names of the code and data labels, by offset into their section
+    code_labels: BTreeMap<usize, Vec<String>>,
+    data_labels: BTreeMap<usize, Vec<String>>,
+    //the symbol the linker puts into the code at an offset, for objects that aren't linked yet
+    relocations: BTreeMap<usize, String>,
+}
+
+impl Disassembler {
+    pub fn new() -> Self {
+        Self { code_labels: BTreeMap::new(), data_labels: BTreeMap::new(), relocations: BTreeMap::new() }
+    }
+
+    //The symbols an object defines are shown as labels above the rows they point to,
+    //and the ones its instructions refer to next to them.
+    pub fn with_symbols(mut self, object: &Object) -> Self {
+        for symbol in &object.symbols {
+            let labels = match symbol.section {
+                Section::Code => &mut self.code_labels,
+                Section::Data => &mut self.data_labels,
+            };
+            labels.entry(symbol.offset).or_default().push(display_name(&symbol.name).to_string());
+        }
+        for relocation in &object.relocations {
+            let mut name = display_name(&relocation.symbol).to_string();
+            if relocation.addend != 0 {
+                name.push_str(&format!(" + {}", relocation.addend));
+            }
+            self.relocations.insert(relocation.offset, name);
+        }
+        for names in self.code_labels.values_mut().chain(self.data_labels.values_mut()) {
+            names.sort();
+        }
+        self
+    }
+
+    pub fn disassemble(&self, program: &Program) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, ".text");
+        let (instructions, invalid) = program.instructions();
+        for (offset, instruction) in instructions {
+            self.write_labels(&mut out, &self.code_labels, offset);
+            let mut comment = vec![];
+            let end = offset + instruction.size(program.encoding);
+            comment.extend(self.relocations.range(offset..end).map(|(_, name)| name.clone()));
+            if let Some(location) = program.debug_info.lookup(offset) {
+                comment.push(format!("{}:{}", location.path, location.entry.line));
+            }
+            let comment = if comment.is_empty() { String::new() } else { format!("# {}", comment.join("  ")) };
+            let row = format!("{:06x}  {:<24}{}", offset, instruction.to_string(), comment);
+            let _ = writeln!(out, "{}", row.trim_end());
+        }
+        if let Some(offset) = invalid {
+            let _ = writeln!(out, "# not a valid instruction from here on");
+            write_bytes(&mut out, offset, &program.code[offset..]);
+        }
+        if !program.data.is_empty() {
+            let _ = writeln!(out, ".data");
+            let mut starts: Vec<usize> = self.data_labels.keys().copied().filter(|&offset| offset < program.data.len()).collect();
+            if starts.first() != Some(&0) {
+                starts.insert(0, 0);
+            }
+            starts.push(program.data.len());
+            for range in starts.windows(2) {
+                self.write_labels(&mut out, &self.data_labels, range[0]);
+                write_bytes(&mut out, DATA_BASE + range[0], &program.data[range[0]..range[1]]);
+            }
+        }
+        out
+    }
+
+    fn write_labels(&self, out: &mut String, labels: &BTreeMap<usize, Vec<String>>, offset: usize) {
+        for name in labels.get(&offset).into_iter().flatten() {
+            let _ = writeln!(out, "{}:", name);
+        }
+    }
+}
+
+//hex rows with the address of their first byte
+fn write_bytes(out: &mut String, address: usize, bytes: &[u8]) {
+    for (row, chunk) in bytes.chunks(ROW_BYTES).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let _ = writeln!(out, "{:06x}  {}", address + row * ROW_BYTES, hex.join(" "));
+    }
+}
@@ -0,0 +1,248 @@
+use crate::carpet_assembler::assembler::Section;
+use std::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+}
+
+impl BinaryOperator {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Sub => "-",
+            BinaryOperator::Mul => "*",
+            BinaryOperator::Div => "/",
+            BinaryOperator::Rem => "%",
+            BinaryOperator::Shl => "<<",
+            BinaryOperator::Shr => ">>",
+            BinaryOperator::And => "&",
+            BinaryOperator::Or => "|",
+            BinaryOperator::Xor => "^",
+        }
+    }
+}
+
+//An operand as it was written, with .equ constants already replaced by their
+//values. Labels stay symbolic until the code is laid out.
+#[derive(Debug, Clone)]
+pub enum Expression {
+    Number(u32),
+    Symbol(String),
+    Negate(Box<Expression>),
+    Not(Box<Expression>),
+    Binary(BinaryOperator, Box<Expression>, Box<Expression>),
+}
+
+//what a value is relative to: the start of a section of the object being
+//assembled, or a symbol that only the linker knows the address of
+#[derive(Debug, Clone, PartialEq)]
+pub enum Atom {
+    Section(Section),
+    Symbol(String),
+}
+
+//constant + the sum of coefficient * atom, arithmetic wraps around at 32 bits
+#[derive(Debug, Clone)]
+pub struct Value {
+    pub constant: u32,
+    pub terms: Vec<(Atom, u32)>,
+}
+
+impl Value {
+    pub fn constant(constant: u32) -> Self {
+        Self { constant, terms: vec![] }
+    }
+
+    pub fn relative(atom: Atom, offset: u32) -> Self {
+        Self { constant: offset, terms: vec![(atom, 1)] }
+    }
+
+    pub fn is_constant(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    fn add(mut self, other: Value) -> Value {
+        self.constant = self.constant.wrapping_add(other.constant);
+        for (atom, coefficient) in other.terms {
+            match self.terms.iter_mut().find(|(existing, _)| *existing == atom) {
+                Some((_, existing)) => *existing = existing.wrapping_add(coefficient),
+                None => self.terms.push((atom, coefficient)),
+            }
+        }
+        self.terms.retain(|(_, coefficient)| *coefficient != 0);
+        self
+    }
+
+    fn scale(mut self, factor: u32) -> Value {
+        self.constant = self.constant.wrapping_mul(factor);
+        for (_, coefficient) in &mut self.terms {
+            *coefficient = coefficient.wrapping_mul(factor);
+        }
+        self.terms.retain(|(_, coefficient)| *coefficient != 0);
+        self
+    }
+}
+
+impl Expression {
+    //every label the expression uses, in the order they're written
+    pub fn symbols(&self) -> Vec<&str> {
+        let mut symbols = vec![];
+        self.collect_symbols(&mut symbols);
+        symbols
+    }
+
+    fn collect_symbols<'a>(&'a self, symbols: &mut Vec<&'a str>) {
+        match self {
+            Expression::Number(_) => {}
+            Expression::Symbol(name) => symbols.push(name),
+            Expression::Negate(operand) | Expression::Not(operand) => operand.collect_symbols(symbols),
+            Expression::Binary(_, left, right) => {
+                left.collect_symbols(symbols);
+                right.collect_symbols(symbols);
+            }
+        }
+    }
+
+    pub fn rename_symbols(&mut self, rename: &mut impl FnMut(&str) -> String) {
+        match self {
+            Expression::Number(_) => {}
+            Expression::Symbol(name) => *name = rename(name),
+            Expression::Negate(operand) | Expression::Not(operand) => operand.rename_symbols(rename),
+            Expression::Binary(_, left, right) => {
+                left.rename_symbols(rename);
+                right.rename_symbols(rename);
+            }
+        }
+    }
+
+    //Labels can be added and subtracted and multiplied by constants, every other
+    //operator needs both sides to be constant once the labels are known.
+    pub fn evaluate(&self, symbol: &impl Fn(&str) -> Value) -> Result<Value, Box<dyn Error>> {
+        let value = match self {
+            Expression::Number(number) => Value::constant(*number),
+            Expression::Symbol(name) => symbol(name),
+            Expression::Negate(operand) => operand.evaluate(symbol)?.scale(u32::MAX),
+            Expression::Not(operand) => Value::constant(!constant(operand.evaluate(symbol)?, "~")?),
+            Expression::Binary(operator, left, right) => {
+                let left = left.evaluate(symbol)?;
+                let right = right.evaluate(symbol)?;
+                match operator {
+                    BinaryOperator::Add => left.add(right),
+                    BinaryOperator::Sub => left.add(right.scale(u32::MAX)),
+                    BinaryOperator::Mul if left.is_constant() => right.scale(left.constant),
+                    BinaryOperator::Mul => left.scale(constant(right, "*")?),
+                    _ => {
+                        let left = constant(left, operator.symbol())?;
+                        let right = constant(right, operator.symbol())?;
+                        Value::constant(apply(*operator, left, right)?)
+                    }
+                }
+            }
+        };
+        Ok(value)
+    }
+}
+
+fn constant(value: Value, operator: &str) -> Result<u32, Box<dyn Error>> {
+    if !value.is_constant() {
+        return Err(format!("the address of a label can't be used with {}", operator).into());
+    }
+    Ok(value.constant)
+}
+
+//division, remainder and >> treat their operands as signed, like div and mod do
+fn apply(operator: BinaryOperator, left: u32, right: u32) -> Result<u32, Box<dyn Error>> {
+    let value = match operator {
+        BinaryOperator::Div | BinaryOperator::Rem if right == 0 => return Err("division by zero".into()),
+        BinaryOperator::Div => (left as i32).wrapping_div(right as i32) as u32,
+        BinaryOperator::Rem => (left as i32).wrapping_rem(right as i32) as u32,
+        BinaryOperator::Shl => left.checked_shl(right).unwrap_or(0),
+        BinaryOperator::Shr => (left as i32).checked_shr(right).unwrap_or(if (left as i32) < 0 { -1 } else { 0 }) as u32,
+        BinaryOperator::And => left & right,
+        BinaryOperator::Or => left | right,
+        BinaryOperator::Xor => left ^ right,
+        BinaryOperator::Add => left.wrapping_add(right),
+        BinaryOperator::Sub => left.wrapping_sub(right),
+        BinaryOperator::Mul => left.wrapping_mul(right),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::expression::parse_expression;
+    use std::collections::HashMap;
+
+    //start is at offset 8 of the code, end at 20 and data at 4 of the data, extern is in another object
+    fn label(name: &str) -> Value {
+        match name {
+            "start" => Value::relative(Atom::Section(Section::Code), 8),
+            "end" => Value::relative(Atom::Section(Section::Code), 20),
+            "data" => Value::relative(Atom::Section(Section::Data), 4),
+            _ => Value::relative(Atom::Symbol(name.to_string()), 0),
+        }
+    }
+
+    fn evaluate(text: &str) -> Result<Value, Box<dyn Error>> {
+        parse_expression(text, &HashMap::new())?.evaluate(&label)
+    }
+
+    fn constant_of(text: &str) -> u32 {
+        let value = evaluate(text).unwrap();
+        assert!(value.is_constant(), "{} isn't constant", text);
+        value.constant
+    }
+
+    #[test]
+    fn signed_division_remainder_and_shift() {
+        assert_eq!(constant_of("-7/2") as i32, -3);
+        assert_eq!(constant_of("-7%2") as i32, -1);
+        assert_eq!(constant_of("7%-2") as i32, 1);
+        assert_eq!(constant_of("-8>>1") as i32, -4);
+        assert_eq!(constant_of("-1>>40") as i32, -1);
+        assert_eq!(constant_of("1<<40"), 0);
+        assert_eq!(constant_of("0x80000000/-1"), 0x80000000);
+    }
+
+    #[test]
+    fn label_differences_are_constant() {
+        assert_eq!(constant_of("end-start"), 12);
+        assert_eq!(constant_of("(end-start)/4"), 3);
+        assert_eq!(constant_of("extern-extern"), 0);
+        assert_eq!(constant_of("2*start-start-start"), 0);
+    }
+
+    #[test]
+    fn labels_with_offsets_stay_relative() {
+        let value = evaluate("start+4").unwrap();
+        assert_eq!(value.constant, 12);
+        assert_eq!(value.terms, vec![(Atom::Section(Section::Code), 1)]);
+        let value = evaluate("extern+end-start").unwrap();
+        assert_eq!(value.constant, 12);
+        assert_eq!(value.terms, vec![(Atom::Symbol("extern".to_string()), 1)]);
+        let value = evaluate("data-start").unwrap();
+        assert_eq!(value.terms.len(), 2);
+    }
+
+    #[test]
+    fn errors() {
+        let message = |text: &str| evaluate(text).unwrap_err().to_string();
+        assert_eq!(message("1/0"), "division by zero");
+        assert_eq!(message("4%(2-2)"), "division by zero");
+        assert_eq!(message("start*end"), "the address of a label can't be used with *");
+        assert_eq!(message("start/2"), "the address of a label can't be used with /");
+        assert_eq!(message("~start"), "the address of a label can't be used with ~");
+        assert_eq!(message("start&0xff"), "the address of a label can't be used with &");
+    }
+}
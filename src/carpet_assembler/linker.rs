@@ -0,0 +1,199 @@
+use crate::carpet::memory::{self, DATA_BASE, DATA_SIZE, WORD_BYTES};
+use crate::carpet::program::Program;
+use crate::carpet_assembler::assembler::Section;
+use crate::carpet_assembler::object::Object;
+use std::collections::HashMap;
+use std::error::Error;
+
+pub struct Linker {}
+
+impl Linker {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    //Places the objects one after the other, in the order given, and sets every
+    //relocation to the final address of its symbol plus its addend. A relocation is resolved
+    //against the symbols of its own object first, then against exported ones.
+    pub fn link(&self, objects: &[Object]) -> Result<Program, Box<dyn Error>> {
+        let mut program = Program::default();
+        if let Some(first) = objects.first() {
+            program.encoding = first.encoding;
+        }
+        if objects.iter().any(|object| object.encoding != program.encoding) {
+            return Err("objects with different instruction encodings can't be linked together".into());
+        }
+        let mut bases = vec![];
+        for object in objects {
+            while program.data.len() % WORD_BYTES != 0 {
+                program.data.push(0);
+            }
+            let code_base = program.code.len();
+            bases.push((code_base, program.data.len()));
+            program.code.extend(&object.code);
+            program.data.extend(&object.data);
+            program.debug_info.append(&object.debug_info, code_base);
+        }
+        if program.data.len() > DATA_SIZE {
+            return Err(format!(
+                "{} bytes of static data don't fit in the {} byte data region",
+                program.data.len(),
+                DATA_SIZE
+            ).into());
+        }
+        let mut locals = vec![];
+        let mut exports = HashMap::new();
+        for (object, &(code_base, data_base)) in objects.iter().zip(&bases) {
+            let mut symbols = HashMap::new();
+            for symbol in &object.symbols {
+                let address = match symbol.section {
+                    Section::Code => code_base + symbol.offset,
+                    Section::Data => DATA_BASE + data_base + symbol.offset,
+                };
+                symbols.insert(symbol.name.as_str(), address);
+                if symbol.exported && exports.insert(symbol.name.as_str(), address).is_some() {
+                    return Err(format!("symbol {} is exported more than once", symbol.name).into());
+                }
+            }
+            locals.push(symbols);
+        }
+        for ((object, &(code_base, _)), symbols) in objects.iter().zip(&bases).zip(&locals) {
+            for relocation in &object.relocations {
+                let name = relocation.symbol.as_str();
+                let address = symbols.get(name).or_else(|| exports.get(name))
+                    .ok_or_else(|| format!("undefined symbol {}", name))?;
+                let at = code_base + relocation.offset;
+                let value = (*address as u32).wrapping_add(relocation.addend);
+                memory::write_le(&mut program.code[at..at + WORD_BYTES], value);
+            }
+        }
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet::cvm::CVM;
+    use crate::carpet::instructions::Encoding;
+    use crate::carpet_assembler::assembler::CarpetAssembler;
+    use crate::parser::parse::Parser;
+
+    const LIBRARY: &str = "\
+        .export print_char\n\
+        .export letter\n\
+        .data\n\
+        padding: .string \"ab\"\n\
+        letter: .word 66\n\
+        .text\n\
+        print_char: print r1\n\
+        jmp r21\n";
+
+    const MAIN: &str = "\
+        .import print_char\n\
+        .import letter\n\
+        load r2 letter\n\
+        read r2 r1\n\
+        load r21 back\n\
+        load r3 print_char\n\
+        jmp r3\n\
+        back: hlt\n";
+
+    fn object(path: &str, source: &str, encoding: Encoding) -> Object {
+        let module = Parser::new().parse_source(path, source).unwrap();
+        CarpetAssembler::new().with_encoding(encoding).assemble_object(module).unwrap()
+    }
+
+    fn objects(encoding: Encoding) -> Vec<Object> {
+        vec![object("main.cbc", MAIN, encoding), object("library.cbc", LIBRARY, encoding)]
+    }
+
+    fn output(program: Program) -> Vec<u8> {
+        let mut cvm = CVM::new().with_captured_output();
+        cvm.new_program(program);
+        cvm.run().unwrap();
+        cvm.take_output()
+    }
+
+    fn link_error(objects: &[Object]) -> String {
+        Linker::new().link(objects).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn relocations_get_the_final_addresses() {
+        for encoding in [Encoding::Padded, Encoding::Compact] {
+            let objects = objects(encoding);
+            let program = Linker::new().link(&objects).unwrap();
+            let value_of = |symbol: &str| {
+                let relocation = objects[0].relocations.iter().find(|relocation| relocation.symbol == symbol).unwrap();
+                memory::read_le(&program.code[relocation.offset..relocation.offset + WORD_BYTES])
+            };
+            //the library comes after the code of main, its data after the data of main
+            assert_eq!(value_of("print_char"), objects[0].code.len() as u32);
+            //"ab" and its 0 come before letter
+            assert_eq!(value_of("letter"), (DATA_BASE + 3) as u32);
+            assert_eq!(output(program), b"B");
+        }
+    }
+
+    #[test]
+    fn objects_and_programs_round_trip() {
+        for encoding in [Encoding::Padded, Encoding::Compact] {
+            for object in objects(encoding) {
+                let bytes = object.to_bytes();
+                assert_eq!(Object::from_bytes(&bytes).unwrap().to_bytes(), bytes);
+            }
+            //the same source always gives the same object
+            assert_eq!(object("main.cbc", MAIN, encoding).to_bytes(), objects(encoding)[0].to_bytes());
+            let read: Vec<Object> = objects(encoding).iter().map(|object| Object::from_bytes(&object.to_bytes()).unwrap()).collect();
+            let program = Linker::new().link(&read).unwrap();
+            let bytes = program.to_bytes();
+            let image = Program::from_bytes(&bytes).unwrap();
+            assert_eq!(image.to_bytes(), bytes);
+            assert_eq!(output(image), b"B");
+        }
+    }
+
+    #[test]
+    fn symbols_are_sorted() {
+        let names: Vec<String> = object("library.cbc", LIBRARY, Encoding::Padded).symbols.into_iter().map(|symbol| symbol.name).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+    }
+
+    #[test]
+    fn link_errors() {
+        let library = object("library.cbc", LIBRARY, Encoding::Padded);
+        let main = object("main.cbc", MAIN, Encoding::Padded);
+        assert!(link_error(std::slice::from_ref(&main)).starts_with("undefined symbol "));
+        assert_eq!(link_error(&[main.clone(), library.clone(), library.clone()]), "symbol letter is exported more than once");
+        let compact = object("library.cbc", LIBRARY, Encoding::Compact);
+        assert_eq!(link_error(&[main, compact]), "objects with different instruction encodings can't be linked together");
+    }
+
+    #[test]
+    fn objects_out_of_bounds_are_rejected() {
+        let library = object("library.cbc", LIBRARY, Encoding::Padded);
+        let main = object("main.cbc", MAIN, Encoding::Padded);
+        let rejected = |object: Object| Object::from_bytes(&object.to_bytes()).unwrap_err().to_string();
+        let mut past = library.clone();
+        let code = past.symbols.iter_mut().find(|symbol| symbol.name == "print_char").unwrap();
+        code.offset = library.code.len() + 1;
+        assert!(rejected(past).contains("past the end of its"));
+        let mut past = library.clone();
+        let data = past.symbols.iter_mut().find(|symbol| symbol.name == "letter").unwrap();
+        data.offset = library.data.len() + 1;
+        assert!(rejected(past).contains("past the end of its"));
+        //a symbol can stand at the very end of its section
+        let mut end = library;
+        let code_len = end.code.len();
+        end.symbols.iter_mut().find(|symbol| symbol.name == "print_char").unwrap().offset = code_len;
+        assert!(Object::from_bytes(&end.to_bytes()).is_ok());
+        for offset in [main.code.len() - 3, main.code.len(), u32::MAX as usize - 1] {
+            let mut outside = main.clone();
+            outside.relocations[0].offset = offset;
+            assert!(rejected(outside).contains("outside the"), "{}", offset);
+        }
+    }
+}
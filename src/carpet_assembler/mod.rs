@@ -1 +1,6 @@
-pub mod assembler;
\ No newline at end of file
+pub mod assembler;
+pub mod disassembler;
+pub mod expression;
+pub mod linker;
+pub mod object;
+pub mod optimizer;
\ No newline at end of file
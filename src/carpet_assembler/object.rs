@@ -0,0 +1,107 @@
+use crate::carpet::binary::{self, Reader};
+use crate::carpet::debug_info::DebugInfo;
+use crate::carpet::instructions::Encoding;
+use crate::carpet::memory::WORD_BYTES;
+use crate::carpet::program;
+use crate::carpet_assembler::assembler::Section;
+use std::error::Error;
+
+const MAGIC: &[u8] = b"COBJ";
+
+#[derive(Debug, Clone)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub section: Section,
+    //byte offset into the code or data of the object
+    pub offset: usize,
+    //only exported symbols can be used by other objects
+    pub exported: bool,
+}
+
+//a 32 bit value in the code that the linker sets to the final address of a symbol plus addend
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    pub offset: usize,
+    pub symbol: String,
+    pub addend: u32,
+}
+
+//Assembled code that hasn't been given its final place in a program yet.
+//Every absolute address it contains is listed in relocations.
+#[derive(Debug, Clone, Default)]
+pub struct Object {
+    pub encoding: Encoding,
+    pub code: Vec<u8>,
+    pub data: Vec<u8>,
+    pub symbols: Vec<ObjectSymbol>,
+    pub relocations: Vec<Relocation>,
+    //offsets are relative to the start of the object's code
+    pub debug_info: DebugInfo,
+}
+
+impl Object {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(self.encoding as u8);
+        binary::write_bytes(&mut out, &self.code);
+        binary::write_bytes(&mut out, &self.data);
+        binary::write_u32(&mut out, self.symbols.len() as u32);
+        for symbol in &self.symbols {
+            binary::write_str(&mut out, &symbol.name);
+            out.push(symbol.section as u8);
+            binary::write_u32(&mut out, symbol.offset as u32);
+            out.push(symbol.exported as u8);
+        }
+        binary::write_u32(&mut out, self.relocations.len() as u32);
+        for relocation in &self.relocations {
+            binary::write_u32(&mut out, relocation.offset as u32);
+            binary::write_str(&mut out, &relocation.symbol);
+            binary::write_u32(&mut out, relocation.addend);
+        }
+        self.debug_info.write(&mut out);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut reader = Reader::new(bytes, MAGIC)?;
+        let encoding = program::read_encoding(&mut reader)?;
+        let code = reader.bytes()?;
+        let data = reader.bytes()?;
+        let mut symbols = vec![];
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            let section = match reader.u8()? {
+                0 => Section::Code,
+                1 => Section::Data,
+                other => return Err(format!("invalid section {} for symbol {}", other, name).into()),
+            };
+            let offset = reader.u32()? as usize;
+            //a label can stand at the very end of its section
+            let len = match section {
+                Section::Code => code.len(),
+                Section::Data => data.len(),
+            };
+            if offset > len {
+                return Err(format!("symbol {} at {} is past the end of its {} byte section", name, offset, len).into());
+            }
+            let exported = reader.u8()? != 0;
+            symbols.push(ObjectSymbol { name, section, offset, exported });
+        }
+        let mut relocations = vec![];
+        for _ in 0..reader.u32()? {
+            let offset = reader.u32()? as usize;
+            let symbol = reader.string()?;
+            let addend = reader.u32()?;
+            if offset.checked_add(WORD_BYTES).is_none_or(|end| end > code.len()) {
+                return Err(format!("relocation of {} at {} is outside the {} bytes of code", symbol, offset, code.len()).into());
+            }
+            relocations.push(Relocation { offset, symbol, addend });
+        }
+        let debug_info = if reader.is_at_end() {
+            DebugInfo::default()
+        } else {
+            DebugInfo::read(&mut reader)?
+        };
+        Ok(Self { encoding, code, data, symbols, relocations, debug_info })
+    }
+}
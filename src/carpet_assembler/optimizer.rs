@@ -0,0 +1,451 @@
+use crate::carpet::instructions::{Encoding, Register, CI};
+use crate::carpet_assembler::assembler::{Module, Reference, Section, SourceLine};
+use crate::carpet_assembler::expression::Expression;
+
+//an instruction of the module being optimized, removed ones stay in place so
+//that labels and jump targets keep their original indices until the end
+#[derive(Debug, Clone)]
+struct Slot {
+    instruction: CI,
+    source: SourceLine,
+    reference: Option<Expression>,
+    //the original index of the instruction a numeric jump address points to
+    target: Option<usize>,
+    removed: bool,
+}
+
+pub struct Optimizer {
+    encoding: Encoding,
+}
+
+impl Optimizer {
+    pub fn new(encoding: Encoding) -> Self {
+        Self { encoding }
+    }
+
+    //Applies the peephole rules until none of them matches any more:
+    //  mov r r                   is removed
+    //  spush r, spop r           cancel out and are both removed when r holds 0, any
+    //                            other amount may overflow the stack
+    //  load r ...                is removed when r is written again before it is read
+    //                            and nothing in between can trap
+    //  load r n                  is removed when r already holds n from the same load
+    //  swrite a i, sread i a     the sread is removed, a already holds that slot
+    //push itself is always kept, it writes the stack and may overflow it.
+    //Labels on removed instructions move to the next instruction that is kept. A
+    //number loaded straight into the register a jump uses is taken as an address and
+    //moved along with the instruction it points to. Modules that use relative jumps,
+    //jump to numbers that aren't the start of an instruction or the end of the code,
+    //or use a label of the code in anything but a bare operand, are left as they are.
+    //So are modules that load any other number that could be an address in the part
+    //of the code that moves, it may be pushed or stored and jumped to later.
+    pub fn optimize(&self, module: Module) -> Module {
+        let mut slots = match self.slots(&module) {
+            Some(slots) => slots,
+            None => return module,
+        };
+        loop {
+            let landings = self.landings(&module, &slots);
+            if !self.pass(&mut slots, &landings) {
+                break;
+            }
+        }
+        if self.moves_numbers(&module, &slots) {
+            return module;
+        }
+        self.rebuild(module, slots)
+    }
+
+    fn slots(&self, module: &Module) -> Option<Vec<Slot>> {
+        if module.code.iter().any(|instruction| matches!(instruction, CI::JMPF(_) | CI::JMPB(_))) {
+            return None;
+        }
+        let offsets = self.layout(&module.code);
+        let mut slots: Vec<Slot> = module.code.iter().zip(&module.code_lines).map(|(instruction, source)| Slot {
+            instruction: *instruction,
+            source: source.clone(),
+            reference: None,
+            target: None,
+            removed: false,
+        }).collect();
+        for reference in &module.references {
+            //only a bare label moves along with the instruction it names, target+4 or
+            //end-start would have to be worked out again from the new layout
+            let bare = matches!(reference.expression, Expression::Symbol(_));
+            if !bare && reference.expression.symbols().iter().any(|name| is_code_label(module, name)) {
+                return None;
+            }
+            slots[reference.at].reference = Some(reference.expression.clone());
+        }
+        for (index, slot) in slots.iter_mut().enumerate() {
+            if let CI::LOAD(register, number) = slot.instruction {
+                if slot.reference.is_none() && jumps_to(&module.code[index + 1..], register) {
+                    //a jump into an instruction or past the end traps, wherever the code ends up
+                    slot.target = Some(offsets.binary_search(&(number as usize)).ok()?);
+                }
+            }
+        }
+        Some(slots)
+    }
+
+    //for every original index, whether something may jump to it
+    fn landings(&self, module: &Module, slots: &[Slot]) -> Vec<bool> {
+        let mut landings = vec![false; slots.len() + 1];
+        for symbol in module.symbols.values().filter(|symbol| symbol.section == Section::Code) {
+            landings[symbol.offset] = true;
+        }
+        for slot in slots.iter().filter(|slot| !slot.removed) {
+            if let Some(target) = slot.target {
+                landings[target] = true;
+            }
+        }
+        landings
+    }
+
+    //whether a kept load of a number that isn't taken as an address loads one from
+    //where the code moves, from the first removed instruction up to the end
+    fn moves_numbers(&self, module: &Module, slots: &[Slot]) -> bool {
+        let first = match slots.iter().position(|slot| slot.removed) {
+            Some(first) => first,
+            None => return false,
+        };
+        let offsets = self.layout(&module.code);
+        let moved = offsets[first]..=offsets[offsets.len() - 1];
+        slots.iter()
+            .filter(|slot| !slot.removed && slot.reference.is_none() && slot.target.is_none())
+            .any(|slot| matches!(slot.instruction, CI::LOAD(_, number) if moved.contains(&(number as usize))))
+    }
+
+    //one sweep over the kept instructions, tells if anything changed
+    fn pass(&self, slots: &mut [Slot], landings: &[bool]) -> bool {
+        let live: Vec<usize> = (0..slots.len()).filter(|&index| !slots[index].removed).collect();
+        //a jump to a removed instruction lands on the next kept one
+        let mut landing = vec![false; live.len()];
+        let mut previous = 0;
+        for (position, &index) in live.iter().enumerate() {
+            landing[position] = landings[previous..=index].iter().any(|&landing| landing);
+            previous = index + 1;
+        }
+        let mut changed = false;
+        for position in 0..live.len() {
+            let index = live[position];
+            if slots[index].removed {
+                continue;
+            }
+            //the instruction that always runs right after this one, if there is one
+            let next = live.get(position + 1).copied()
+                .filter(|&next| !landing[position + 1] && !slots[next].removed)
+                .map(|next| (next, slots[next].instruction));
+            match (slots[index].instruction, next) {
+                (CI::MOV(register, out), _) if register == out => {
+                    slots[index].removed = true;
+                }
+                (CI::SPUSH(pushed), Some((next, CI::SPOP(popped))))
+                    if pushed == popped && holds(slots, &live, &landing, position, pushed, 0) =>
+                {
+                    slots[index].removed = true;
+                    slots[next].removed = true;
+                }
+                (CI::LOAD(register, _), _) if overwritten(slots, &live[position + 1..], register) => {
+                    slots[index].removed = true;
+                }
+                (CI::LOAD(register, number), _)
+                    if slots[index].reference.is_none() && slots[index].target.is_none()
+                        && holds(slots, &live, &landing, position, register, number) =>
+                {
+                    slots[index].removed = true;
+                }
+                (CI::SWRITE(value, index), Some((next, CI::SREAD(read, out)))) if read == index && out == value => {
+                    slots[next].removed = true;
+                }
+                _ => continue,
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    fn rebuild(&self, mut module: Module, slots: Vec<Slot>) -> Module {
+        //the new index of every original one, removed instructions map to the next kept one
+        let mut new_index = Vec::with_capacity(slots.len() + 1);
+        let mut count = 0;
+        for slot in &slots {
+            new_index.push(count);
+            if !slot.removed {
+                count += 1;
+            }
+        }
+        new_index.push(count);
+        for symbol in module.symbols.values_mut().filter(|symbol| symbol.section == Section::Code) {
+            symbol.offset = new_index[symbol.offset];
+        }
+        let kept: Vec<Slot> = slots.into_iter().filter(|slot| !slot.removed).collect();
+        module.code = kept.iter().map(|slot| slot.instruction).collect();
+        let offsets = self.layout(&module.code);
+        for (instruction, slot) in module.code.iter_mut().zip(&kept) {
+            if let (CI::LOAD(register, _), Some(target)) = (*instruction, slot.target) {
+                *instruction = CI::LOAD(register, offsets[new_index[target]] as u32);
+            }
+        }
+        module.references = kept.iter().enumerate()
+            .filter_map(|(at, slot)| slot.reference.clone().map(|expression| Reference { at, expression }))
+            .collect();
+        module.code_lines = kept.into_iter().map(|slot| slot.source).collect();
+        module
+    }
+
+    //byte offset of every instruction, followed by the offset of the end of the code
+    fn layout(&self, instructions: &[CI]) -> Vec<usize> {
+        let mut offsets = vec![0];
+        for instruction in instructions {
+            offsets.push(offsets[offsets.len() - 1] + instruction.size(self.encoding));
+        }
+        offsets
+    }
+}
+
+fn is_code_label(module: &Module, name: &str) -> bool {
+    module.symbols.get(name).is_some_and(|symbol| symbol.section == Section::Code)
+}
+
+//whether the code that follows, up to the first unconditional jump, jumps to the
+//address in register before anything else is written to it
+fn jumps_to(code: &[CI], register: Register) -> bool {
+    for instruction in code {
+        match instruction {
+            CI::JMP(target) | CI::JEQ(_, target) | CI::JNE(_, target) if *target == register => return true,
+            _ if instruction.writes() == Some(register) => return false,
+            CI::JMP(_) | CI::HLT | CI::EXIT(_) => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+//Whether register is written before it is read, going through the kept instructions
+//in order until the first jump. A trap on the way would leave the old value for the
+//debugger and the register expectations of tests to see.
+fn overwritten(slots: &[Slot], live: &[usize], register: Register) -> bool {
+    for slot in live.iter().map(|&index| &slots[index]).filter(|slot| !slot.removed) {
+        let instruction = &slot.instruction;
+        if instruction.reads().contains(&register) || may_trap(instruction) {
+            return false;
+        }
+        if instruction.writes() == Some(register) {
+            return true;
+        }
+        if matches!(instruction, CI::JMP(_) | CI::JMPF(_) | CI::JMPB(_) | CI::JEQ(..) | CI::JNE(..) | CI::HLT | CI::EXIT(_)) {
+            return false;
+        }
+    }
+    false
+}
+
+//Whether register holds number, put there by a load of that number, whenever the kept
+//instruction at position runs. Goes back through the instructions that fall through
+//to it, one that something may jump to is as far as it gets.
+fn holds(slots: &[Slot], live: &[usize], landing: &[bool], position: usize, register: Register, number: u32) -> bool {
+    for earlier in (0..position).rev() {
+        if landing[earlier + 1] {
+            return false;
+        }
+        let slot = &slots[live[earlier]];
+        if slot.removed {
+            continue;
+        }
+        let loads = matches!(slot.instruction, CI::LOAD(loaded, value) if loaded == register && value == number);
+        if loads && slot.reference.is_none() && slot.target.is_none() {
+            return true;
+        }
+        if slot.instruction.writes() == Some(register) {
+            return false;
+        }
+    }
+    false
+}
+
+//the instructions that can trap, other than by running out of fuel
+fn may_trap(instruction: &CI) -> bool {
+    matches!(instruction,
+        CI::DIV(..) | CI::MOD(..) | CI::PUSH(_) | CI::SPUSH(_) | CI::POP(_) | CI::SPOP(_)
+        | CI::SREAD(..) | CI::SWRITE(..) | CI::READ(..) | CI::WRITE(..) | CI::LOAD8U(..) | CI::LOAD8S(..)
+        | CI::LOAD16U(..) | CI::LOAD16S(..) | CI::STORE8(..) | CI::STORE16(..) | CI::MALLOC(..) | CI::FREE(_)
+        | CI::PRINTS(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet::cvm::{CVM, REGISTER_COUNT};
+    use crate::carpet_assembler::assembler::CarpetAssembler;
+    use crate::parser::parse::Parser;
+
+    //what a run leaves behind: how it ended, what it printed and the registers
+    type Outcome = (Result<i32, String>, Vec<u8>, [u32; REGISTER_COUNT]);
+
+    fn run(module: Module, encoding: Encoding) -> Outcome {
+        let program = CarpetAssembler::new().with_encoding(encoding).assemble(module).unwrap();
+        let mut cvm = CVM::new().with_fuel(1_000_000).with_captured_output();
+        cvm.new_program(program);
+        let result = cvm.run().map_err(|error| error.to_string());
+        let mut registers = *cvm.registers();
+        //the scratch registers of the pseudo-instructions hold addresses in the code, which move
+        registers[30..].fill(0);
+        (result, cvm.take_output(), registers)
+    }
+
+    //runs source as it is and optimized, both have to end the same way
+    fn compare(path: &str, source: &str, encoding: Encoding) -> Outcome {
+        let module = Parser::new().parse_source(path, source).unwrap();
+        let optimized = Optimizer::new(encoding).optimize(module.clone());
+        let outcome = run(module, encoding);
+        assert_eq!(outcome, run(optimized, encoding), "{} with {:?}", path, encoding);
+        outcome
+    }
+
+    //how many instructions the pass takes out of source
+    fn removed(source: &str) -> usize {
+        let module = Parser::new().parse_source("removed.cbc", source).unwrap();
+        let before = module.code.len();
+        before - Optimizer::new(Encoding::Padded).optimize(module).code.len()
+    }
+
+    fn check(path: &str, source: &str) -> Outcome {
+        let outcome = compare(path, source, Encoding::Padded);
+        assert_eq!(outcome, compare(path, source, Encoding::Compact), "{} in both encodings", path);
+        outcome
+    }
+
+    //the numeric jump addresses in the examples are written for the padded encoding
+    #[test]
+    fn examples_run_the_same() {
+        for name in ["example", "pseudo", "codegen", "cc_cg", "heap", "hello", "numbers"] {
+            let path = format!("cbc/{}.cbc", name);
+            let (result, _, _) = compare(&path, &std::fs::read_to_string(&path).unwrap(), Encoding::Padded);
+            assert!(result.is_ok(), "{}: {:?}", path, result);
+        }
+    }
+
+    #[test]
+    fn jump_past_the_end_still_traps() {
+        let (result, _, _) = check("past.cbc", "\
+            loadi r1 5\n\
+            mov r1 r1\n\
+            load r2 1000\n\
+            jmp r2\n");
+        assert!(result.unwrap_err().contains("jump to 0x3e8"));
+    }
+
+    #[test]
+    fn pushed_address_keeps_its_target() {
+        //the addresses are those of the padded encoding
+        let (result, output, _) = compare("return.cbc", "\
+            load r5 28\n\
+            push r5\n\
+            mov r1 r1\n\
+            load r6 32\n\
+            jmp r6\n\
+            hlt\n\
+            loadi r7 66\n\
+            print r7\n\
+            pop r8\n\
+            jmp r8\n", Encoding::Padded);
+        assert_eq!(result, Ok(0));
+        assert_eq!(output, b"B");
+    }
+
+    #[test]
+    fn label_with_an_offset_keeps_its_target() {
+        //a mov takes 4 bytes in the padded encoding
+        let (result, _, registers) = compare("offset.cbc", "\
+            loadi r3 1\n\
+            load r1 target+4\n\
+            jmp r1\n\
+            target: mov r2 r2\n\
+            loadi r3 2\n\
+            hlt\n\
+            loadi r3 3\n", Encoding::Padded);
+        assert_eq!(result, Ok(0));
+        assert_eq!(registers[3], 2);
+    }
+
+    #[test]
+    fn push_still_writes_the_stack() {
+        let (result, _, registers) = check("push.cbc", "\
+            loadi r0 0x1000\n\
+            loadi r1 7\n\
+            push r1\n\
+            pop r3\n\
+            read r0 r2\n");
+        assert_eq!(result, Ok(0));
+        assert_eq!((registers[2], registers[3]), (7, 7));
+    }
+
+    #[test]
+    fn push_still_overflows() {
+        let (result, _, _) = check("overflow.cbc", "\
+            loadi r1 255\n\
+            spush r1\n\
+            push r1\n\
+            push r1\n\
+            pop r2\n");
+        assert!(result.unwrap_err().contains("stack overflow"));
+    }
+
+    #[test]
+    fn removed_instructions_move_their_labels() {
+        let module = Parser::new().parse_source("nop.cbc", "\
+            loadi r1 0\n\
+            loop: nop\n\
+            inc r1\n\
+            loadi r2 3\n\
+            blt r1 r2 loop\n").unwrap();
+        let before = module.code.len();
+        let optimized = Optimizer::new(Encoding::Padded).optimize(module.clone());
+        assert!(optimized.code.len() < before);
+        let (result, _, registers) = run(optimized, Encoding::Padded);
+        assert_eq!(result, Ok(0));
+        assert_eq!(registers[1], 3);
+        assert_eq!(run(module, Encoding::Padded).2, registers);
+    }
+
+    #[test]
+    fn loads_of_what_a_register_holds_are_removed() {
+        let source = "\
+            loadi r1 65\n\
+            print r1\n\
+            loadi r1 65\n\
+            print r1\n\
+            loadi r2 0\n\
+            swrite r1 r2\n\
+            sread r2 r1\n\
+            print r1\n";
+        let (_, output, _) = check("reload.cbc", source);
+        assert_eq!(output, b"AAA");
+        assert_eq!(removed(source), 2);
+        //a label in between may be reached with anything in r1
+        assert_eq!(removed("loadi r1 5\nprint r1\nagain: loadi r1 5\nprint r1\n"), 0);
+    }
+
+    #[test]
+    fn stack_pairs_are_removed_only_when_they_cant_trap() {
+        let (result, _, _) = check("spush.cbc", "loadi r1 300\nspush r1\nspop r1\n");
+        assert!(result.unwrap_err().contains("stack overflow"));
+        assert_eq!(removed("loadi r1 300\nspush r1\nspop r1\n"), 0);
+        assert_eq!(check("zero.cbc", "loadi r1 0\nspush r1\nspop r1\n").0, Ok(0));
+        assert_eq!(removed("loadi r1 0\nspush r1\nspop r1\n"), 2);
+    }
+
+    #[test]
+    fn loads_before_a_trap_are_kept() {
+        let source = "\
+            loadi r1 5\n\
+            loadi r0 0\n\
+            div r1 r0 r2\n\
+            loadi r1 6\n";
+        let (result, _, registers) = check("trap.cbc", source);
+        assert!(result.unwrap_err().contains("division by zero"));
+        assert_eq!(registers[1], 5);
+        assert_eq!(removed(source), 0);
+    }
+}
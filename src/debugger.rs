@@ -0,0 +1,357 @@
+use crate::carpet::cvm::CVM;
+use crate::carpet::program::Program;
+use crate::carpet::snapshot::Snapshot;
+use crate::carpet::tracer::Change;
+use crate::carpet::undo::UndoLog;
+use crate::parser::number::parse_unsigned;
+use crate::repl::{memory_range, print_bytes, print_registers, print_stack};
+use crate::watchpoint::Watchpoint;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+step [n]             runs the next n instructions, 1 without n, s for short
+continue             runs until a breakpoint or the end of the program, c for short
+back [n]             takes back the last n instructions, 1 without n, what they printed stays
+reverse-continue     runs backwards until a breakpoint or as far as the program is recorded,
+                     rc for short
+break where          stops before the instruction at where, an address like 0x18,
+                     a line of the program or file.cbc:line, b for short
+delete [n]           removes breakpoint n, or all of them without n
+breakpoints          lists the breakpoints
+watch what           stops after an instruction that changes what, r5, stack[12], heap[300]
+                     for the word at that offset into the heap, or an address like 0x10130
+watch what written   stops after an instruction that writes what, also with the same value
+watch what == value  stops after an instruction that writes value to what
+unwatch [n]          removes watchpoint n, or all of them without n
+watchpoints          lists the watchpoints
+regs                 shows the registers
+stack                shows the values on the stack, from the bottom up
+mem ptr len          shows the len bytes at address ptr
+where                shows the instruction that runs next
+save file            writes a snapshot of the program as it is now to file
+load file            goes back to the snapshot in file, which can be of another program
+restart              starts the program over with a new CVM
+help                 shows this
+quit                 leaves the debugger, so does end of input
+an empty line repeats the last command";
+
+//carpet debug: runs a program an instruction at a time, new_cvm makes the CVM it
+//runs on, again for every restart. The CVM records what the instructions change,
+//so they can be taken back.
+pub fn debug(program: Program, new_cvm: impl Fn() -> CVM<UndoLog>) -> Result<(), Box<dyn Error>> {
+    let mut cvm = new_cvm();
+    cvm.new_program(program.clone());
+    let mut breakpoints: Vec<usize> = vec![];
+    let mut watchpoints: Vec<Watchpoint> = vec![];
+    //whether the program halted or trapped, it can only be restarted then
+    let mut ended = false;
+    let mut last = String::new();
+    println!("carpet debug, help lists the commands");
+    print_where(&cvm);
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(debug) ");
+        io::stdout().flush()?;
+        let mut line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        if line.trim().is_empty() {
+            line = last.clone();
+        }
+        last = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, arguments) = match words.split_first() {
+            Some((command, arguments)) => (*command, arguments),
+            None => continue,
+        };
+        match command {
+            "step" | "s" | "continue" | "c" => {
+                if ended {
+                    println!("the program has ended, restart runs it again");
+                    continue;
+                }
+                let count = match (command, arguments.first()) {
+                    ("continue", _) | ("c", _) => None,
+                    (_, Some(count)) => match count.parse::<usize>() {
+                        Ok(count) => Some(count),
+                        Err(_) => {
+                            eprintln!("invalid count {}", count);
+                            continue;
+                        }
+                    },
+                    (_, None) => Some(1),
+                };
+                ended = run(&mut cvm, count, &breakpoints, &watchpoints);
+                if !ended {
+                    print_where(&cvm);
+                }
+            }
+            "back" | "reverse-continue" | "rc" => {
+                let count = match (command, arguments.first()) {
+                    ("back", Some(count)) => match count.parse::<usize>() {
+                        Ok(count) => Some(count),
+                        Err(_) => {
+                            eprintln!("invalid count {}", count);
+                            continue;
+                        }
+                    },
+                    ("back", None) => Some(1),
+                    _ => None,
+                };
+                if run_back(&mut cvm, count, &breakpoints, &watchpoints) > 0 {
+                    ended = false;
+                }
+                print_where(&cvm);
+            }
+            "break" | "b" => match arguments.first().map(|location| breakpoint(&cvm, location)) {
+                Some(Ok(offset)) => {
+                    let index = match breakpoints.iter().position(|&breakpoint| breakpoint == offset) {
+                        Some(index) => index,
+                        None => {
+                            breakpoints.push(offset);
+                            breakpoints.len() - 1
+                        }
+                    };
+                    println!("breakpoint {} at {:#08x}  {}", index, offset, location(&cvm, offset));
+                }
+                Some(Err(error)) => eprintln!("{}", error),
+                None => eprintln!("break needs an address or a line"),
+            },
+            "delete" => match arguments.first() {
+                Some(index) => match index.parse::<usize>() {
+                    Ok(index) if index < breakpoints.len() => {
+                        breakpoints.remove(index);
+                    }
+                    _ => eprintln!("no breakpoint {}", index),
+                },
+                None => breakpoints.clear(),
+            },
+            "breakpoints" => {
+                for (index, offset) in breakpoints.iter().enumerate() {
+                    println!("{:>4}: {:#08x}  {}", index, offset, location(&cvm, *offset));
+                }
+            }
+            "watch" => match Watchpoint::parse(arguments) {
+                Ok(watchpoint) => {
+                    println!("watchpoint {}: {}", watchpoints.len(), watchpoint);
+                    watchpoints.push(watchpoint);
+                }
+                Err(error) => eprintln!("{}", error),
+            },
+            "unwatch" => match arguments.first() {
+                Some(index) => match index.parse::<usize>() {
+                    Ok(index) if index < watchpoints.len() => {
+                        watchpoints.remove(index);
+                    }
+                    _ => eprintln!("no watchpoint {}", index),
+                },
+                None => watchpoints.clear(),
+            },
+            "watchpoints" => {
+                for (index, watchpoint) in watchpoints.iter().enumerate() {
+                    println!("{:>4}: {}", index, watchpoint);
+                }
+            }
+            "regs" => print_registers(cvm.registers()),
+            "stack" => print_stack(&cvm.stack()),
+            "mem" => match memory_range(arguments) {
+                Ok((address, len)) => match cvm.read_memory(address, len) {
+                    Ok(bytes) => print_bytes(address, &bytes),
+                    Err(trap) => eprintln!("CVM {}", trap),
+                },
+                Err(error) => eprintln!("{}", error),
+            },
+            "where" => print_where(&cvm),
+            "save" => match arguments.first() {
+                Some(path) => match fs::write(path, cvm.snapshot().to_bytes()) {
+                    Ok(()) => println!("saved to {}", path),
+                    Err(error) => eprintln!("{}: {}", path, error),
+                },
+                None => eprintln!("save needs a file"),
+            },
+            "load" => match arguments.first().map(|path| load(path)) {
+                Some(Ok(snapshot)) => {
+                    cvm.restore(&snapshot);
+                    ended = cvm.exit_code().is_some() || cvm.next_instruction().is_none();
+                    print_where(&cvm);
+                }
+                Some(Err(error)) => eprintln!("{}", error),
+                None => eprintln!("load needs a file"),
+            },
+            "restart" => {
+                cvm = new_cvm();
+                cvm.new_program(program.clone());
+                ended = false;
+                print_where(&cvm);
+            }
+            "help" => println!("{}", HELP),
+            "quit" | "q" => break,
+            _ => eprintln!("unknown command {}, help lists the commands", command),
+        }
+    }
+    println!();
+    Ok(())
+}
+
+//Runs count instructions, or without a count until the program ends, stopping before
+//any instruction that has a breakpoint except the first and after one that sets off a
+//watchpoint. Tells if the program ended.
+fn run(cvm: &mut CVM<UndoLog>, count: Option<usize>, breakpoints: &[usize], watchpoints: &[Watchpoint]) -> bool {
+    let mut ran = 0;
+    while count.is_none_or(|count| ran < count) {
+        if ran > 0 {
+            if let Some((pc, _)) = cvm.next_instruction() {
+                if breakpoints.contains(&pc) {
+                    println!("breakpoint at {:#08x}", pc);
+                    return false;
+                }
+            }
+        }
+        let before = values(cvm, watchpoints);
+        match cvm.step() {
+            Ok(true) => {
+                ran += 1;
+                let after = values(cvm, watchpoints);
+                if watched(&cvm.tracer_mut().last(), watchpoints, &before, &after) {
+                    return false;
+                }
+            }
+            Ok(false) => {
+                println!();
+                match cvm.exit_code() {
+                    Some(exit_code) => println!("the program exited with code {}", exit_code),
+                    None => println!("the program ran past its last instruction"),
+                }
+                return true;
+            }
+            Err(error) => {
+                println!();
+                eprintln!("{}", error);
+                return true;
+            }
+        }
+    }
+    false
+}
+
+//Takes back count instructions, or without a count all of them, stopping once the
+//instruction of a breakpoint or one that set off a watchpoint is the next to run
+//again. Tells how many were taken back.
+fn run_back(cvm: &mut CVM<UndoLog>, count: Option<usize>, breakpoints: &[usize], watchpoints: &[Watchpoint]) -> usize {
+    let mut undone = 0;
+    while count.is_none_or(|count| undone < count) {
+        let after = values(cvm, watchpoints);
+        let changes = cvm.tracer_mut().last();
+        if !cvm.step_back() {
+            println!("the program isn't recorded any further back");
+            break;
+        }
+        undone += 1;
+        if watched(&changes, watchpoints, &values(cvm, watchpoints), &after) {
+            break;
+        }
+        if count.is_some_and(|count| undone == count) {
+            break;
+        }
+        if let Some((pc, _)) = cvm.next_instruction() {
+            if breakpoints.contains(&pc) {
+                println!("breakpoint at {:#08x}", pc);
+                break;
+            }
+        }
+    }
+    undone
+}
+
+fn values(cvm: &mut CVM<UndoLog>, watchpoints: &[Watchpoint]) -> Vec<Option<u32>> {
+    watchpoints.iter().map(|watchpoint| watchpoint.value(cvm)).collect()
+}
+
+//tells if an instruction that made changes set off a watchpoint, and which ones it did
+fn watched(changes: &[Change], watchpoints: &[Watchpoint], before: &[Option<u32>], after: &[Option<u32>]) -> bool {
+    let show = |value: Option<u32>| value.map_or("unmapped".to_string(), |value| (value as i32).to_string());
+    let mut fired = false;
+    for (index, watchpoint) in watchpoints.iter().enumerate() {
+        if watchpoint.fires(changes, before[index], after[index]) {
+            println!("watchpoint {}: {}, {} -> {}", index, watchpoint, show(before[index]), show(after[index]));
+            fired = true;
+        }
+    }
+    fired
+}
+
+//an address when it starts with 0x, otherwise a line, of the first file or of the file before the :
+fn breakpoint(cvm: &CVM<UndoLog>, location: &str) -> Result<usize, Box<dyn Error>> {
+    if location.starts_with("0x") {
+        return Ok(parse_unsigned(location).ok_or_else(|| format!("invalid address {}", location))?);
+    }
+    let (file, line) = match location.rsplit_once(':') {
+        Some((file, line)) => (Some(file), line),
+        None => (None, location),
+    };
+    let line: usize = line.parse().map_err(|_| format!("invalid line {}", line))?;
+    let debug_info = cvm.debug_info();
+    let file = match file {
+        Some(file) => debug_info.files.iter().position(|path| path == file || path.ends_with(&format!("/{}", file))),
+        None => (!debug_info.files.is_empty()).then_some(0),
+    };
+    debug_info.lines.iter()
+        .find(|entry| Some(entry.file) == file && entry.line == line)
+        .map(|entry| entry.offset)
+        .ok_or_else(|| format!("no code on line {}", location).into())
+}
+
+fn load(path: &str) -> Result<Snapshot, Box<dyn Error>> {
+    let bytes = fs::read(path).map_err(|error| format!("{}: {}", path, error))?;
+    Snapshot::from_bytes(&bytes).map_err(|error| format!("{}: {}", path, error).into())
+}
+
+fn location(cvm: &CVM<UndoLog>, offset: usize) -> String {
+    match cvm.debug_info().lookup(offset) {
+        Some(location) => location.to_string(),
+        None => String::new(),
+    }
+}
+
+fn print_where(cvm: &CVM<UndoLog>) {
+    match cvm.next_instruction() {
+        Some((pc, Some(instruction))) => println!("{:#08x}  {:<22}{}", pc, instruction.to_string(), location(cvm, pc)),
+        Some((pc, None)) => println!("{:#08x}  not a valid instruction", pc),
+        None => println!("at the end of the program"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet_assembler::assembler::CarpetAssembler;
+    use crate::parser::parse::Parser;
+
+    fn finished(source: &str) -> CVM<UndoLog> {
+        let module = Parser::new().parse_source("back.cbc", source).unwrap();
+        let mut cvm = CVM::with_tracer(UndoLog::new()).with_captured_output();
+        cvm.new_program(CarpetAssembler::new().assemble(module).unwrap());
+        cvm.run().unwrap();
+        cvm
+    }
+
+    #[test]
+    fn back_and_reverse_continue() {
+        let mut cvm = finished("loadi r1 1\ninc r1\ninc r1\ninc r1\ninc r1\n");
+        assert_eq!(cvm.registers()[1], 5);
+        assert_eq!(run_back(&mut cvm, Some(2), &[], &[]), 2);
+        assert_eq!(cvm.registers()[1], 3);
+        //stops with the instruction on line 2 up next, r1 as it was before it ran
+        let line_2 = breakpoint(&cvm, "2").unwrap();
+        assert_eq!(run_back(&mut cvm, None, &[line_2], &[]), 2);
+        assert_eq!(cvm.next_instruction().map(|(pc, _)| pc), Some(line_2));
+        assert_eq!(cvm.registers()[1], 1);
+        assert_eq!(run_back(&mut cvm, None, &[line_2], &[]), 1);
+        assert_eq!(cvm.registers()[1], 0);
+        assert_eq!(run_back(&mut cvm, None, &[], &[]), 0);
+    }
+}
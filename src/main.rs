@@ -1,29 +1,561 @@
-use crate::carpet::cvm::CVM;
+#![allow(clippy::upper_case_acronyms)]
+
+use crate::carpet::coverage::Coverage;
+use crate::carpet::cvm::{CVM, STACK_SIZE};
+use crate::carpet::cvm_heap::{CVMHeap, HEAP_SIZE};
+use crate::carpet::instructions::Encoding;
+use crate::carpet::memory::HEAP_BASE;
+use crate::carpet::profiler::Profiler;
+use crate::carpet::program::Program;
+use crate::carpet::tracer::{InstructionTracer, NoTracer, TraceFormat, Tracer};
+use crate::carpet::undo::UndoLog;
 use crate::carpet_assembler::assembler::{CarpetAssembler};
+use crate::carpet_assembler::disassembler::Disassembler;
+use crate::carpet_assembler::linker::Linker;
+use crate::carpet_assembler::object::Object;
+use crate::carpet_assembler::optimizer::Optimizer;
+use crate::parser::format::format_source;
+use crate::parser::number::parse_unsigned;
 use crate::parser::parse::Parser;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::process;
 use std::time::Instant;
 
 mod carpet;
 mod carpet_assembler;
+mod debugger;
 mod parser;
+mod repl;
+mod test_runner;
+mod watchpoint;
+
+const RUN: &str = "run";
+const ASM: &str = "asm";
+const DISASM: &str = "disasm";
+const CHECK: &str = "check";
+const DEBUG: &str = "debug";
+const FMT: &str = "fmt";
+const TEST: &str = "test";
+const REPL: &str = "repl";
+const LINK: &str = "link";
+const HELP: &str = "--help";
+const OUTPUT: &str = "-o";
+const LISTING: &str = "--listing";
+const COMPACT: &str = "--compact";
+const OPTIMIZE: &str = "--optimize";
+const LEAK_REPORT: &str = "--leak-report";
+const TRACE: &str = "--trace";
+const TRACE_JSON: &str = "--trace-json";
+const PROFILE: &str = "--profile";
+const PROFILE_FOLDED: &str = "--profile-folded";
+const COVERAGE: &str = "--coverage";
+const STACK_SIZE_OPTION: &str = "--stack-size";
+const HEAP_SIZE_OPTION: &str = "--heap-size";
+const FUEL: &str = "--fuel";
+const QUIET: &str = "--quiet";
+const QUIET_SHORT: &str = "-q";
+const FMT_CHECK: &str = "--check";
+const OBJECT_EXTENSION: &str = "cobj";
+const IMAGE_EXTENSION: &str = "cimg";
+const LISTING_EXTENSION: &str = "lst";
+
+//exit codes of carpet itself, carpet run exits with the code of the first program that
+//didn't exit with 0 instead, a program that traps counts as EXIT_TRAP
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_TRAP: i32 = 3;
+
+const USAGE: &str = "\
+usage: carpet <command> [options] file...
+
+commands:
+  run       runs .cbc source or linked .cimg programs, the command can be left out
+  asm       assembles .cbc source to .cobj objects
+  link      links .cobj objects to a .cimg program
+  disasm    prints the instructions of a .cbc, .cobj or .cimg file
+  check     assembles and links .cbc source without writing anything
+  debug     runs a program an instruction at a time
+  fmt       formats .cbc source in place
+  test      runs the .cbc programs in directories that say what they should do
+  repl      runs assembly as it is typed
+
+options:
+  -o file                   the file asm, link, disasm and fmt write, for a single input
+  --compact                 uses the compact instruction encoding
+  --optimize                runs the peephole optimizer before assembling
+  --listing                 asm also writes a .lst listing next to the source
+  --stack-size slots        run, debug and test: the stack holds at most slots values, 256 at most
+  --heap-size bytes         run, debug and test: the size of the heap, 0x40000 at most
+  --fuel n                  run, debug and test: stops a program once it has run n instructions
+  -q, --quiet               run: only prints what the programs print, and errors
+  --leak-report             run: reports the heap blocks a program didn't free
+  --trace                   run: writes every instruction that runs to stderr
+  --trace-json file         run: the same as json lines to file
+  --profile                 run: prints the hottest instructions and blocks
+  --profile-folded file     run: writes folded stacks for flamegraph.pl to file
+  --coverage file           run: prints a coverage summary and writes lcov to file
+  --check                   fmt: changes nothing and fails if a file isn't formatted
+  --help                    shows this
+
+exit codes:
+  0  success
+  1  a file couldn't be read, assembled or linked, isn't formatted, or a test failed
+  2  the command line is wrong
+  3  a program trapped
+  run exits with the code of the first program that exit gave one other than 0";
+
+//a mistake on the command line, carpet exits with EXIT_USAGE for it
+#[derive(Debug)]
+struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+fn usage(message: String) -> Box<dyn Error> {
+    Box::new(UsageError(message))
+}
+
+//the sizes and the fuel every CVM gets, from --stack-size, --heap-size and --fuel
+struct Limits {
+    stack_size: usize,
+    heap_size: usize,
+    fuel: u64,
+}
+
+impl Limits {
+    fn take(args: &mut Vec<String>) -> Result<Self, Box<dyn Error>> {
+        let stack_size = take_number(args, STACK_SIZE_OPTION)?.unwrap_or(STACK_SIZE as u64) as usize;
+        if stack_size > STACK_SIZE {
+            return Err(usage(format!("{} can be {} at most", STACK_SIZE_OPTION, STACK_SIZE)));
+        }
+        let heap_size = take_number(args, HEAP_SIZE_OPTION)?.unwrap_or(HEAP_SIZE as u64) as usize;
+        if heap_size > HEAP_SIZE {
+            return Err(usage(format!("{} can be {:#x} at most", HEAP_SIZE_OPTION, HEAP_SIZE)));
+        }
+        let fuel = take_number(args, FUEL)?.unwrap_or(u64::MAX);
+        Ok(Self { stack_size, heap_size, fuel })
+    }
+
+    fn cvm<T: Tracer>(&self, tracer: T) -> CVM<T> {
+        CVM::with_tracer(tracer)
+            .with_stack_size(self.stack_size)
+            .with_heap_size(self.heap_size)
+            .with_fuel(self.fuel)
+    }
+}
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        None => Err(usage("no command or program given".to_string())),
+        Some(HELP) | Some("help") => {
+            println!("{}", USAGE);
+            Ok(0)
+        }
+        Some(RUN) => run_programs(&args[1..]),
+        Some(ASM) => assemble_objects(&args[1..]).map(|_| 0),
+        Some(DISASM) => disassemble(&args[1..]).map(|_| 0),
+        Some(CHECK) => check(&args[1..]),
+        Some(DEBUG) => debug_program(&args[1..]).map(|_| 0),
+        Some(FMT) => format_files(&args[1..]),
+        Some(TEST) => run_tests(&args[1..]),
+        Some(REPL) => no_options(&args[1..]).and_then(|_| repl::repl()).map(|_| 0),
+        Some(LINK) => link_objects(&args[1..]).map(|_| 0),
+        _ => run_programs(&args),
+    };
+    match result {
+        Ok(code) => process::exit(code),
+        Err(error) => exit_with(error),
+    }
+}
+
+//carpet asm [--listing] [--compact] [--optimize] file.cbc... [-o file.cobj], the listing of file.cbc goes to file.lst
+fn assemble_objects(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut paths = args.to_vec();
+    let output = take_output(&mut paths)?;
+    let listing = take_flag(&mut paths, LISTING);
+    let optimize = take_flag(&mut paths, OPTIMIZE);
+    let encoding = encoding(&mut paths);
+    no_options(&paths)?;
+    let carpet_assembler = CarpetAssembler::new().with_encoding(encoding);
+    if output.is_some() && paths.len() != 1 {
+        return Err(usage(format!("{} can only be used with one file", OUTPUT)));
+    }
     let parser = Parser::new();
-    let mut cvm = CVM::new();
-    for program_path in &args[1..] {
-        let code = parser.parse_ci_asm(&program_path).unwrap();
-        let carpet_assembler = CarpetAssembler::new();
-        let program = carpet_assembler.generate_byte_code(
-            code
-        );
+    for path in &paths {
+        let mut module = parser.parse_ci_asm(path)?;
+        if optimize {
+            module = Optimizer::new(encoding).optimize(module);
+        }
+        if listing {
+            fs::write(with_extension(path, LISTING_EXTENSION), carpet_assembler.listing(&module)?)?;
+        }
+        let object = carpet_assembler.assemble_object(module)?;
+        let output = output.clone().unwrap_or_else(|| with_extension(path, OBJECT_EXTENSION));
+        fs::write(&output, object.to_bytes())?;
+    }
+    Ok(())
+}
+
+//carpet link file.cobj... [-o program.cimg]
+fn link_objects(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut paths = args.to_vec();
+    let output = take_output(&mut paths)?;
+    no_options(&paths)?;
+    let first = paths.first().ok_or_else(|| usage("no object files to link".to_string()))?;
+    let mut objects = vec![];
+    for path in &paths {
+        let object = Object::from_bytes(&fs::read(path)?)
+            .map_err(|error| format!("{}: {}", path, error))?;
+        objects.push(object);
+    }
+    let program = Linker::new().link(&objects)?;
+    let output = output.unwrap_or_else(|| with_extension(first, IMAGE_EXTENSION));
+    fs::write(output, program.to_bytes())?;
+    Ok(())
+}
+
+//carpet disasm [--compact] file [-o file.txt], where file is .cbc source, a .cobj object or a linked .cimg
+fn disassemble(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut paths = args.to_vec();
+    let output = take_output(&mut paths)?;
+    let encoding = encoding(&mut paths);
+    no_options(&paths)?;
+    let path = match paths.as_slice() {
+        [path] => path,
+        _ => return Err(usage(format!("{} needs exactly one file", DISASM))),
+    };
+    let text = if has_extension(path, IMAGE_EXTENSION) {
+        Disassembler::new().disassemble(&Program::from_bytes(&fs::read(path)?)?)
+    } else {
+        let object = if has_extension(path, OBJECT_EXTENSION) {
+            Object::from_bytes(&fs::read(path)?).map_err(|error| format!("{}: {}", path, error))?
+        } else {
+            CarpetAssembler::new().with_encoding(encoding).assemble_object(Parser::new().parse_ci_asm(path)?)?
+        };
+        //offsets are the object's own, as if it was linked on its own
+        let program = Program {
+            encoding: object.encoding,
+            code: object.code.clone(),
+            data: object.data.clone(),
+            debug_info: object.debug_info.clone(),
+        };
+        Disassembler::new().with_symbols(&object).disassemble(&program)
+    };
+    match output {
+        Some(output) => fs::write(output, text)?,
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+//carpet check [--compact] file.cbc..., every file is assembled and all of them are linked together
+fn check(args: &[String]) -> Result<i32, Box<dyn Error>> {
+    let mut paths = args.to_vec();
+    let encoding = encoding(&mut paths);
+    no_options(&paths)?;
+    if paths.is_empty() {
+        return Err(usage(format!("{} needs a file", CHECK)));
+    }
+    let parser = Parser::new();
+    let carpet_assembler = CarpetAssembler::new().with_encoding(encoding);
+    let mut objects = vec![];
+    for path in &paths {
+        match parser.parse_ci_asm(path).and_then(|module| carpet_assembler.assemble_object(module)) {
+            Ok(object) => objects.push(object),
+            Err(error) => eprintln!("{}", error),
+        }
+    }
+    if objects.len() < paths.len() {
+        return Ok(EXIT_ERROR);
+    }
+    if let Err(error) = Linker::new().link(&objects) {
+        eprintln!("{}", error);
+        return Ok(EXIT_ERROR);
+    }
+    Ok(0)
+}
+
+//carpet debug [--compact] [--optimize] [--stack-size n] [--heap-size n] [--fuel n] program
+fn debug_program(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut paths = args.to_vec();
+    let limits = Limits::take(&mut paths)?;
+    let optimize = take_flag(&mut paths, OPTIMIZE);
+    let encoding = encoding(&mut paths);
+    no_options(&paths)?;
+    let path = match paths.as_slice() {
+        [path] => path,
+        _ => return Err(usage(format!("{} needs exactly one program", DEBUG))),
+    };
+    let optimizer = optimize.then(|| Optimizer::new(encoding));
+    let program = load_program(path, &CarpetAssembler::new().with_encoding(encoding), optimizer.as_ref())?;
+    debugger::debug(program, || limits.cvm(UndoLog::new()))
+}
+
+//carpet fmt [--check] file.cbc... [-o file.cbc]
+fn format_files(args: &[String]) -> Result<i32, Box<dyn Error>> {
+    let mut paths = args.to_vec();
+    let output = take_output(&mut paths)?;
+    let check = take_flag(&mut paths, FMT_CHECK);
+    no_options(&paths)?;
+    if paths.is_empty() || (output.is_some() && paths.len() != 1) {
+        return Err(usage(format!("{} needs a file, and exactly one with {}", FMT, OUTPUT)));
+    }
+    let mut code = 0;
+    for path in &paths {
+        let source = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+        let formatted = format_source(&source);
+        if check {
+            if formatted != source {
+                eprintln!("{} is not formatted", path);
+                code = EXIT_ERROR;
+            }
+        } else if let Some(output) = &output {
+            fs::write(output, formatted)?;
+        } else if formatted != source {
+            fs::write(path, formatted)?;
+        }
+    }
+    Ok(code)
+}
+
+//carpet test [--compact] [--optimize] [--stack-size n] [--heap-size n] [--fuel n] dir-or-file.cbc...
+fn run_tests(args: &[String]) -> Result<i32, Box<dyn Error>> {
+    let mut paths = args.to_vec();
+    let limits = Limits::take(&mut paths)?;
+    let optimize = take_flag(&mut paths, OPTIMIZE);
+    let encoding = encoding(&mut paths);
+    no_options(&paths)?;
+    if paths.is_empty() {
+        return Err(usage(format!("{} needs a directory or a file", TEST)));
+    }
+    let optimizer = optimize.then(|| Optimizer::new(encoding));
+    let carpet_assembler = CarpetAssembler::new().with_encoding(encoding);
+    let passed = test_runner::run_tests(
+        &paths,
+        |path| load_program(path, &carpet_assembler, optimizer.as_ref()),
+        || limits.cvm(NoTracer).with_captured_output(),
+    )?;
+    Ok(if passed { 0 } else { EXIT_ERROR })
+}
+
+//carpet [run] [-q] [--leak-report] [--compact] [--optimize] [--stack-size n] [--heap-size n] [--fuel n]
+//             [--trace | --trace-json file.jsonl] [--profile] [--profile-folded file.folded]
+//             [--coverage file.info] program...
+//where a program is .cbc source or a linked .cimg, the text trace goes to stderr
+fn run_programs(args: &[String]) -> Result<i32, Box<dyn Error>> {
+    let mut args = args.to_vec();
+    let trace_json = take_value(&mut args, TRACE_JSON)?;
+    let trace = take_flag(&mut args, TRACE);
+    let profile_folded = take_value(&mut args, PROFILE_FOLDED)?;
+    let profile = take_flag(&mut args, PROFILE);
+    let coverage = take_value(&mut args, COVERAGE)?;
+    let leak_report = take_flag(&mut args, LEAK_REPORT);
+    let quiet = take_flag(&mut args, QUIET) | take_flag(&mut args, QUIET_SHORT);
+    let limits = Limits::take(&mut args)?;
+    let optimize = take_flag(&mut args, OPTIMIZE);
+    let encoding = encoding(&mut args);
+    no_options(&args)?;
+    if args.is_empty() {
+        return Err(usage("no program to run".to_string()));
+    }
+    let optimizer = optimize.then(|| Optimizer::new(encoding));
+    let carpet_assembler = CarpetAssembler::new().with_encoding(encoding);
+    let options = RunOptions { assembler: &carpet_assembler, optimizer: optimizer.as_ref(), leak_report, quiet };
+    let trace = match (trace_json, trace) {
+        (Some(path), _) => Some(InstructionTracer::new(Box::new(BufWriter::new(File::create(path)?)), TraceFormat::JsonLines)),
+        (None, true) => Some(InstructionTracer::new(Box::new(io::stderr()), TraceFormat::Text)),
+        (None, false) => None,
+    };
+    let profiler = (profile || profile_folded.is_some()).then(Profiler::new);
+    let coverage_tracer = coverage.is_some().then(Coverage::new);
+    if trace.is_none() && profiler.is_none() && coverage_tracer.is_none() {
+        return run_each(&mut limits.cvm(NoTracer), &args, &options, |_| Ok(()));
+    }
+    let mut cvm = limits.cvm((trace, (profiler, coverage_tracer)));
+    let mut folded = String::new();
+    let mut lcov = String::new();
+    let code = run_each(&mut cvm, &args, &options, |(_, (profiler, coverage))| {
+        if let Some(profiler) = profiler {
+            if profile {
+                print!("{}", profiler.report());
+            }
+            folded.push_str(&profiler.folded());
+        }
+        if let Some(coverage) = coverage {
+            println!("{}", coverage.summary());
+            lcov.push_str(&coverage.lcov());
+        }
+        Ok(())
+    })?;
+    if let Some(path) = profile_folded {
+        fs::write(path, folded)?;
+    }
+    if let Some(path) = coverage {
+        fs::write(path, lcov)?;
+    }
+    if let (Some(trace), _) = cvm.tracer_mut() {
+        trace.finish()?;
+    }
+    Ok(code)
+}
+
+//how run_each loads the programs and what it prints around them
+struct RunOptions<'a> {
+    assembler: &'a CarpetAssembler,
+    optimizer: Option<&'a Optimizer>,
+    leak_report: bool,
+    quiet: bool,
+}
+
+//Runs the programs one after the other, finished is called with the tracer after each
+//one. Returns the code of the first program that exited with one other than 0, or
+//EXIT_TRAP if that one trapped, 0 if none did.
+fn run_each<T: Tracer>(
+    cvm: &mut CVM<T>,
+    paths: &[String],
+    options: &RunOptions,
+    mut finished: impl FnMut(&mut T) -> Result<(), Box<dyn Error>>,
+) -> Result<i32, Box<dyn Error>> {
+    let mut code = 0;
+    for program_path in paths {
+        let program = load_program(program_path, options.assembler, options.optimizer)?;
         cvm.new_program(
             program
         );
 
         let time = Instant::now();
-        cvm.run();
-        println!();
-        println!("program ran in {:?}", time.elapsed());
+        let result = cvm.run();
+        if !options.quiet {
+            match cvm.exit_code() {
+                Some(0) => println!("Program terminated successfully"),
+                Some(exit_code) => println!("Program exited with code {}", exit_code),
+                None => {}
+            }
+            println!();
+        }
+        let exit_code = match result {
+            Ok(exit_code) => exit_code,
+            Err(error) => {
+                eprintln!("{}", error);
+                EXIT_TRAP
+            }
+        };
+        if code == 0 {
+            code = exit_code;
+        }
+        if !options.quiet {
+            println!("program ran in {:?}", time.elapsed());
+        }
+        if options.leak_report {
+            print_leak_report(cvm.heap());
+        }
+        finished(cvm.tracer_mut())?;
+    }
+    Ok(code)
+}
+
+//a linked .cimg as it is, anything else is assembled as .cbc source
+fn load_program(path: &str, carpet_assembler: &CarpetAssembler, optimizer: Option<&Optimizer>) -> Result<Program, Box<dyn Error>> {
+    if has_extension(path, IMAGE_EXTENSION) {
+        return Program::from_bytes(&fs::read(path)?);
+    }
+    let mut module = Parser::new().parse_ci_asm(path)?;
+    if let Some(optimizer) = optimizer {
+        module = optimizer.optimize(module);
+    }
+    carpet_assembler.assemble(module)
+}
+
+//removes -o and its value from the arguments and returns the value
+fn take_output(args: &mut Vec<String>) -> Result<Option<String>, Box<dyn Error>> {
+    take_value(args, OUTPUT)
+}
+
+//removes an option that takes a file name and its value from the arguments and returns the value
+fn take_value(args: &mut Vec<String>, option: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let index = match args.iter().position(|arg| arg == option) {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+    if index + 1 >= args.len() {
+        return Err(usage(format!("{} needs a value", option)));
+    }
+    let output = args.remove(index + 1);
+    args.remove(index);
+    Ok(Some(output))
+}
+
+//the same for an option that takes a decimal or 0x hex number
+fn take_number(args: &mut Vec<String>, option: &str) -> Result<Option<u64>, Box<dyn Error>> {
+    let value = match take_value(args, option)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match parse_unsigned(&value) {
+        Some(number) => Ok(Some(number)),
+        None => Err(usage(format!("{} needs a number, not {}", option, value))),
+    }
+}
+
+//removes every occurrence of flag from the arguments and tells if there was one
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != len
+}
+
+//once the options a command knows are taken, anything else that looks like one is a mistake
+fn no_options(args: &[String]) -> Result<(), Box<dyn Error>> {
+    match args.iter().find(|arg| arg.starts_with('-')) {
+        Some(arg) => Err(usage(format!("unknown option {}", arg))),
+        None => Ok(()),
+    }
+}
+
+fn encoding(args: &mut Vec<String>) -> Encoding {
+    if take_flag(args, COMPACT) {
+        Encoding::Compact
+    } else {
+        Encoding::Padded
+    }
+}
+
+fn has_extension(path: &str, extension: &str) -> bool {
+    Path::new(path).extension() == Some(OsStr::new(extension))
+}
+
+fn with_extension(path: &str, extension: &str) -> String {
+    Path::new(path).with_extension(extension).display().to_string()
+}
+
+fn print_leak_report(heap: &CVMHeap) {
+    for line in heap.stats().to_string().lines() {
+        println!("heap: {}", line);
+    }
+    for leak in heap.leaks() {
+        println!(
+            "leak: {} bytes at {:#x} allocated by MALLOC at pc={}",
+            leak.size, HEAP_BASE + leak.ptr, leak.origin
+        );
+    }
+}
+
+fn exit_with(error: Box<dyn Error>) -> ! {
+    eprintln!("{}", error);
+    if error.is::<UsageError>() {
+        eprintln!("carpet {} lists the commands and options", HELP);
+        process::exit(EXIT_USAGE);
     }
-}
\ No newline at end of file
+    process::exit(EXIT_ERROR);
+}
@@ -0,0 +1,206 @@
+use crate::carpet_assembler::expression::{BinaryOperator, Expression};
+use crate::parser::parse::escaped;
+use std::collections::HashMap;
+use std::error::Error;
+use std::iter::Peekable;
+use std::str::Chars;
+
+//operators from the loosest to the tightest binding, every level is left associative
+const PRECEDENCE: &[&[(&str, BinaryOperator)]] = &[
+    &[("|", BinaryOperator::Or)],
+    &[("^", BinaryOperator::Xor)],
+    &[("&", BinaryOperator::And)],
+    &[("<<", BinaryOperator::Shl), (">>", BinaryOperator::Shr)],
+    &[("+", BinaryOperator::Add), ("-", BinaryOperator::Sub)],
+    &[("*", BinaryOperator::Mul), ("/", BinaryOperator::Div), ("%", BinaryOperator::Rem)],
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u32),
+    Name(String),
+    Operator(String),
+    Open,
+    Close,
+}
+
+//Parses an operand like label+8, (SIZE*4)-1, 'A', 0x41 or 0b1010. Names that
+//are .equ constants are replaced by their value, the rest are labels.
+pub fn parse_expression(text: &str, constants: &HashMap<String, u32>) -> Result<Expression, Box<dyn Error>> {
+    let tokens = lex(text)?;
+    let mut position = 0;
+    let expression = parse_level(&tokens, &mut position, 0, constants)?;
+    match tokens.get(position) {
+        None => Ok(expression),
+        Some(_) => Err(format!("invalid expression {}", text).into()),
+    }
+}
+
+fn parse_level(tokens: &[Token], position: &mut usize, level: usize, constants: &HashMap<String, u32>) -> Result<Expression, Box<dyn Error>> {
+    let operators = match PRECEDENCE.get(level) {
+        Some(operators) => operators,
+        None => return parse_unary(tokens, position, constants),
+    };
+    let mut left = parse_level(tokens, position, level + 1, constants)?;
+    while let Some(Token::Operator(symbol)) = tokens.get(*position) {
+        let operator = match operators.iter().find(|(candidate, _)| candidate == symbol) {
+            Some((_, operator)) => *operator,
+            None => break,
+        };
+        *position += 1;
+        let right = parse_level(tokens, position, level + 1, constants)?;
+        left = Expression::Binary(operator, Box::new(left), Box::new(right));
+    }
+    Ok(left)
+}
+
+fn parse_unary(tokens: &[Token], position: &mut usize, constants: &HashMap<String, u32>) -> Result<Expression, Box<dyn Error>> {
+    let token = tokens.get(*position).ok_or("expression ends too early")?;
+    *position += 1;
+    match token {
+        Token::Number(number) => Ok(Expression::Number(*number)),
+        Token::Name(name) => Ok(match constants.get(name) {
+            Some(value) => Expression::Number(*value),
+            None => Expression::Symbol(name.clone()),
+        }),
+        Token::Operator(symbol) if symbol == "-" => Ok(Expression::Negate(Box::new(parse_unary(tokens, position, constants)?))),
+        Token::Operator(symbol) if symbol == "~" => Ok(Expression::Not(Box::new(parse_unary(tokens, position, constants)?))),
+        Token::Operator(symbol) if symbol == "+" => parse_unary(tokens, position, constants),
+        Token::Open => {
+            let expression = parse_level(tokens, position, 0, constants)?;
+            if tokens.get(*position) != Some(&Token::Close) {
+                return Err("missing )".into());
+            }
+            *position += 1;
+            Ok(expression)
+        }
+        Token::Operator(symbol) => Err(format!("unexpected {} in expression", symbol).into()),
+        Token::Close => Err("unexpected ) in expression".into()),
+    }
+}
+
+fn lex(text: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            '\'' => tokens.push(Token::Number(character(&mut chars)?)),
+            '0'..='9' => tokens.push(Token::Number(number(&mut chars)?)),
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_' || **c == '@') {
+                    name.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Name(name));
+            }
+            '<' | '>' => {
+                chars.next();
+                if chars.next() != Some(c) {
+                    return Err(format!("invalid operator {}, did you mean {}{}", c, c, c).into());
+                }
+                tokens.push(Token::Operator(format!("{}{}", c, c)));
+            }
+            '+' | '-' | '*' | '/' | '%' | '&' | '|' | '^' | '~' => {
+                chars.next();
+                tokens.push(Token::Operator(c.to_string()));
+            }
+            c => return Err(format!("unexpected {} in expression", c).into()),
+        }
+    }
+    Ok(tokens)
+}
+
+//42, 0x2a or 0b101010
+fn number(chars: &mut Peekable<Chars>) -> Result<u32, Box<dyn Error>> {
+    let mut digits = String::new();
+    while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_') {
+        digits.push(c);
+        chars.next();
+    }
+    let (radix, value) = if let Some(hex) = digits.strip_prefix("0x") {
+        (16, hex)
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        (2, binary)
+    } else {
+        (10, digits.as_str())
+    };
+    u32::from_str_radix(&value.replace('_', ""), radix).map_err(|_| format!("invalid number {}", digits).into())
+}
+
+//'A' or an escape sequence like '\n', the value is the code point of the character
+fn character(chars: &mut Peekable<Chars>) -> Result<u32, Box<dyn Error>> {
+    chars.next();
+    let c = match chars.next() {
+        Some('\\') => escaped(chars.next())?,
+        Some('\'') | None => return Err("empty character literal".into()),
+        Some(c) => c,
+    };
+    if chars.next() != Some('\'') {
+        return Err("a character literal holds exactly one character".into());
+    }
+    Ok(c as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet_assembler::expression::Value;
+
+    fn evaluate(text: &str) -> Result<u32, Box<dyn Error>> {
+        let constants = HashMap::from([("SIZE".to_string(), 3)]);
+        let value = parse_expression(text, &constants)?.evaluate(&|name| panic!("no label {}", name))?;
+        assert!(value.is_constant());
+        Ok(value.constant)
+    }
+
+    fn parses_to(text: &str, expected: u32) {
+        assert_eq!(evaluate(text).unwrap(), expected, "{}", text);
+    }
+
+    #[test]
+    fn precedence() {
+        parses_to("1+2*3", 7);
+        parses_to("(1+2)*3", 9);
+        parses_to("8-2-1", 5);
+        parses_to("16/4/2", 2);
+        parses_to("1<<2+1", 8);
+        parses_to("1|2^3&4", 3);
+        parses_to("6&3|8", 10);
+        parses_to("-2*3", (-6i32) as u32);
+        parses_to("~0&0xff", 0xff);
+        parses_to("SIZE*4-1", 11);
+    }
+
+    #[test]
+    fn literals() {
+        parses_to("0x41", 0x41);
+        parses_to("0b1010", 10);
+        parses_to("1_000", 1000);
+        parses_to("'A'", 65);
+        parses_to("'\\n'", 10);
+        parses_to("'A'+1", 66);
+    }
+
+    #[test]
+    fn labels_stay_symbolic() {
+        let expression = parse_expression("target+4", &HashMap::new()).unwrap();
+        assert_eq!(expression.symbols(), vec!["target"]);
+        let value: Value = expression.evaluate(&|_| Value::constant(0x20)).unwrap();
+        assert_eq!(value.constant, 0x24);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        for text in ["(1+2", "1+", "1 < 2", "''", "'ab'", "0x", "1 2", ")", "1 $ 2"] {
+            assert!(parse_expression(text, &HashMap::new()).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//trailing comments of a paragraph start on the first multiple of this past the longest code and a space
+const COMMENT_TAB: usize = 8;
+
+//a source line split into its code, with single spaces between tokens, and its comment
+struct Line {
+    code: String,
+    comment: Option<String>,
+}
+
+//Formats carpet assembly the one way carpet fmt writes it: every line starts in
+//the first column, tokens are separated by a single space, the trailing comments
+//of a paragraph are lined up and there is at most one blank line in a row.
+//Spaces inside quotes and parentheses are kept, so the code assembles the same.
+pub fn format_source(source: &str) -> String {
+    let mut out = String::new();
+    let lines: Vec<Line> = source.lines().map(split_line).collect();
+    for paragraph in lines.split(|line| line.code.is_empty() && line.comment.is_none()) {
+        if paragraph.is_empty() {
+            continue;
+        }
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        let width = paragraph.iter()
+            .filter(|line| !line.code.is_empty() && line.comment.is_some())
+            .map(|line| line.code.chars().count())
+            .max()
+            .unwrap_or(0);
+        let column = (width + 1).div_ceil(COMMENT_TAB) * COMMENT_TAB;
+        for line in paragraph {
+            match &line.comment {
+                Some(comment) if line.code.is_empty() => out.push_str(comment),
+                Some(comment) => out.push_str(&format!("{:<column$}{}", line.code, comment, column = column)),
+                None => out.push_str(&line.code),
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn split_line(line: &str) -> Line {
+    let mut code = String::new();
+    let mut chars = line.trim().chars();
+    let mut depth = 0usize;
+    let mut space = false;
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() && depth == 0 {
+            space = true;
+            continue;
+        }
+        if space && !code.is_empty() && c != '#' {
+            code.push(' ');
+        }
+        space = false;
+        match c {
+            '#' => {
+                let comment = format!("#{}", chars.as_str());
+                return Line { code, comment: Some(comment.trim_end().to_string()) };
+            }
+            '"' | '\'' => {
+                code.push(c);
+                //an unterminated quote is an assembler error, the rest of the line is kept as it is
+                while let Some(quoted) = chars.next() {
+                    code.push(quoted);
+                    if quoted == '\\' {
+                        code.extend(chars.next());
+                    } else if quoted == c {
+                        break;
+                    }
+                }
+            }
+            '(' | ')' => {
+                depth = if c == '(' { depth + 1 } else { depth.saturating_sub(1) };
+                code.push(c);
+            }
+            c => code.push(c),
+        }
+    }
+    Line { code, comment: None }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::iter::Peekable;
+use std::str::Chars;
+
+//deeper than this, a macro is assumed to expand itself forever
+pub const MAX_EXPANSION_DEPTH: usize = 64;
+
+//the tokens of a line, with the line and column they start at
+pub type TokenLine = (usize, usize, Vec<String>);
+
+#[derive(Debug)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<String>,
+    pub path: String,
+    pub line: usize,
+    pub body: Vec<TokenLine>,
+}
+
+impl Macro {
+    pub fn check_args(&self, args: &[String]) -> Result<(), Box<dyn Error>> {
+        if args.len() != self.params.len() {
+            return Err(format!(
+                "macro {} takes {} arguments but {} were given",
+                self.name,
+                self.params.len(),
+                args.len()
+            ).into());
+        }
+        Ok(())
+    }
+
+    //labels defined in the body, every expansion renames them to label@id so it gets its own copy
+    pub fn locals(&self) -> HashSet<&str> {
+        self.body.iter()
+            .filter_map(|(_, _, tokens)| tokens.first()?.strip_suffix(':'))
+            .collect()
+    }
+
+    //a line of the body with every \param replaced by its argument and the local labels renamed
+    pub fn expand_line(&self, tokens: &[String], args: &[String], locals: &HashSet<&str>, id: usize) -> Result<Vec<String>, Box<dyn Error>> {
+        tokens.iter().map(|token| self.substitute(token, args, locals, id)).collect()
+    }
+
+    //works on every word of the token, so expressions like \base+4 or loop-8 are substituted too
+    fn substitute(&self, token: &str, args: &[String], locals: &HashSet<&str>, id: usize) -> Result<String, Box<dyn Error>> {
+        let mut substituted = String::new();
+        let mut chars = token.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                //strings and characters are copied as they are, escape sequences included
+                '"' | '\'' => {
+                    substituted.push(c);
+                    while let Some(inner) = chars.next() {
+                        substituted.push(inner);
+                        if inner == '\\' {
+                            substituted.extend(chars.next());
+                        } else if inner == c {
+                            break;
+                        }
+                    }
+                }
+                '\\' => {
+                    let param = word(&mut chars, String::new());
+                    match self.params.iter().position(|name| *name == param) {
+                        Some(index) => substituted.push_str(&args[index]),
+                        None => return Err(format!("macro {} has no parameter {}", self.name, param).into()),
+                    }
+                }
+                c if c.is_ascii_alphanumeric() || c == '_' => {
+                    let word = word(&mut chars, c.to_string());
+                    substituted.push_str(&word);
+                    if locals.contains(word.as_str()) {
+                        substituted.push_str(&format!("@{}", id));
+                    }
+                }
+                c => substituted.push(c),
+            }
+        }
+        Ok(substituted)
+    }
+}
+
+//the rest of a name or number that starts with start
+fn word(chars: &mut Peekable<Chars>, mut start: String) -> String {
+    while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_' || **c == '@') {
+        start.push(c);
+        chars.next();
+    }
+    start
+}
@@ -1 +1,5 @@
+pub mod expression;
+pub mod format;
+mod macros;
+pub mod number;
 pub mod parse;
\ No newline at end of file
@@ -0,0 +1,52 @@
+use std::convert::TryFrom;
+
+//Numbers typed on the command line, into the debugger or into the expectations of a
+//test: decimal with an optional -, or 0x hex. Assembly has its own, richer syntax.
+pub fn parse_number(text: &str) -> Option<i64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok().and_then(|number| i64::try_from(number).ok()),
+        None => text.parse().ok(),
+    }
+}
+
+//a register or memory value, a negative number is stored as its two's complement
+pub fn parse_word(text: &str) -> Option<u32> {
+    parse_number(text)
+        .filter(|number| (i32::MIN as i64..=u32::MAX as i64).contains(number))
+        .map(|number| number as u32)
+}
+
+//an address, a length or a count, which can't be negative
+pub fn parse_unsigned<T: TryFrom<i64>>(text: &str) -> Option<T> {
+    parse_number(text).and_then(|number| T::try_from(number).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words() {
+        assert_eq!(parse_word("42"), Some(42));
+        assert_eq!(parse_word("-1"), Some(u32::MAX));
+        assert_eq!(parse_word("0xffffffff"), Some(u32::MAX));
+        assert_eq!(parse_word("-2147483648"), Some(0x80000000));
+        assert_eq!(parse_word("0x100000000"), None);
+        assert_eq!(parse_word("-2147483649"), None);
+    }
+
+    #[test]
+    fn unsigned() {
+        assert_eq!(parse_unsigned::<usize>("0x1000"), Some(0x1000));
+        assert_eq!(parse_unsigned::<u64>("18"), Some(18));
+        assert_eq!(parse_unsigned::<usize>("-1"), None);
+        assert_eq!(parse_unsigned::<u8>("256"), None);
+    }
+
+    #[test]
+    fn not_numbers() {
+        for text in ["", "0x", "0x-1", "1f", "r1", "1.5", "0b1"] {
+            assert_eq!(parse_number(text), None, "{}", text);
+        }
+    }
+}
@@ -1,6 +1,17 @@
-use crate::carpet_assembler::assembler::CI;
-use std::fs::OpenOptions;
+use crate::carpet::cvm::REGISTER_COUNT;
+use crate::carpet::instructions::CI;
+use crate::carpet_assembler::assembler::{Module, Reference, Section, SourceLine, Symbol};
+use crate::carpet_assembler::expression::Expression;
+use crate::parser::expression::parse_expression;
+use crate::parser::macros::{Macro, MAX_EXPANSION_DEPTH};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, OpenOptions};
 use std::io::Read;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
 
 
 use std::error::Error;
@@ -9,6 +20,7 @@ const LOAD: &str = "load";
 const LOADI: &str = "loadi";
 const LOADF: &str = "loadf";
 const PRINT: &str = "print";
+const PRINTS: &str = "prints";
 const INC: &str = "inc";
 const DEC: &str = "dec";
 const ADD: &str = "add";
@@ -21,6 +33,7 @@ const FSUB: &str = "fsub";
 const FMUL: &str = "fmul";
 const FDIV: &str = "fdiv";
 const HLT: &str = "hlt";
+const EXIT: &str = "exit";
 const JMP: &str = "jmp";
 const JMPB: &str = "jmpb";
 const JMPF: &str = "jmpf";
@@ -42,6 +55,18 @@ const POP: &str = "pop";
 const SPOP: &str = "spop";
 const SREAD: &str = "sread";
 const SWRITE: &str = "swrite";
+const READ: &str = "read";
+const WRITE: &str = "write";
+const HREAD: &str = "hread";
+const HWRITE: &str = "hwrite";
+const LOAD8U: &str = "load8u";
+const LOAD8S: &str = "load8s";
+const LOAD16U: &str = "load16u";
+const LOAD16S: &str = "load16s";
+const LOAD32: &str = "load32";
+const STORE8: &str = "store8";
+const STORE16: &str = "store16";
+const STORE32: &str = "store32";
 const MOV: &str = "mov";
 const JEQ: &str = "jeq";
 const JNE: &str = "jne";
@@ -52,190 +77,1062 @@ const ITOF: &str = "itof";
 const I32: &str = "i32";
 const F32: &str = "f32";
 
+//pseudo-instructions, the assembler expands them to several instructions
+const BEQ: &str = "beq";
+const BNE: &str = "bne";
+const BLT: &str = "blt";
+const BGT: &str = "bgt";
+const BLE: &str = "ble";
+const BGE: &str = "bge";
+const CALL: &str = "call";
+const RET: &str = "ret";
+const NOP: &str = "nop";
+const CLR: &str = "clr";
+const NOT: &str = "not";
+
+
+const TEXT_SECTION: &str = ".text";
+const DATA_SECTION: &str = ".data";
+const WORD: &str = ".word";
+const FLOAT: &str = ".float";
+const STRING: &str = ".string";
+const EQU: &str = ".equ";
+const REG: &str = ".reg";
+pub const MACRO: &str = ".macro";
+pub const ENDM: &str = ".endm";
+const INCLUDE: &str = ".include";
+const EXPORT: &str = ".export";
+const IMPORT: &str = ".import";
+const SCRATCH: &str = ".scratch";
+
+//the registers pseudo-instructions may overwrite, until a .scratch picks others
+const DEFAULT_SCRATCH: (u8, u8) = (31, 30);
+
 
 pub struct Parser {}
 
-/*
-    GTQ(Register, Register, Register),
-    LTQ(Register, Register, Register),
-    FEQ(Register, Register, Register),
-    FNE(Register, Register, Register),
-    FGT(Register, Register, Register),
-    FLT(Register, Register, Register),
-    FGTQ(Register, Register, Register),
-    FLTQ(Register, Register, Register),
-    PUSH(Register),
-    POP(Register),
-    SREAD(Register, Register),
-    SWRITE(Register, Register),
-    MOV(Register, Register),
-    JEQ(Register, Register),
- */
+//a line of a macro that a line was expanded from
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    pub name: String,
+    pub path: String,
+    pub line: usize,
+}
+
+//Where a line comes from. A line expanded from a macro is at the call in the file
+//being parsed, expansions then go down the macros it went through, outermost first.
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub path: String,
+    pub line: usize,
+    pub expansions: Vec<Expansion>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.path, self.line)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub location: Location,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)?;
+        for expansion in &self.location.expansions {
+            write!(f, "\n{}:{}: note: in macro {}", expansion.path, expansion.line, expansion.name)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseError {}
+
+//the module being built while the lines of a file are parsed
+struct ParseState {
+    module: Module,
+    section: Section,
+    path: String,
+    line: usize,
+    column: usize,
+    //the tokens of the line being parsed after its label, with macros expanded
+    text: String,
+    //every file that has been included, the index of a file is the scope of its labels
+    files: Vec<PathBuf>,
+    //files currently being parsed, the innermost .include last
+    include_stack: Vec<usize>,
+    file: usize,
+    labels: HashMap<(usize, String), Symbol>,
+    //exported labels, with the file exporting them and where
+    exports: HashMap<String, (usize, Location)>,
+    imports: HashSet<(usize, String)>,
+    //file and location of every entry in module.references, to resolve them once every label is known
+    reference_sites: Vec<(usize, Location)>,
+    //.equ constants
+    constants: HashMap<String, u32>,
+    //.reg register aliases
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Rc<Macro>>,
+    //pseudo-instructions use the first one, branches use both
+    scratch: (u8, u8),
+    //every call gets a label for the instruction it returns to
+    call_count: usize,
+    //the macro between a .macro and its .endm, which collects the lines in between
+    defining: Option<Macro>,
+    //macro calls currently being expanded, innermost last
+    expansions: Vec<Expansion>,
+    expansion_count: usize,
+    //the line the outermost macro call is on, the debug info puts what it expands to there
+    call_site: Option<SourceLine>,
+}
+
+impl ParseState {
+    fn new(path: &str) -> Self {
+        let file = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        Self {
+            module: Module::default(),
+            section: Section::Code,
+            path: path.to_string(),
+            line: 0,
+            column: 0,
+            text: String::new(),
+            files: vec![file],
+            include_stack: vec![0],
+            file: 0,
+            labels: HashMap::new(),
+            exports: HashMap::new(),
+            imports: HashSet::new(),
+            reference_sites: vec![],
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            scratch: DEFAULT_SCRATCH,
+            call_count: 0,
+            defining: None,
+            expansions: vec![],
+            expansion_count: 0,
+            call_site: None,
+        }
+    }
+
+    //every call is on a line of the macro the one before it expands, the last line is in the innermost macro
+    fn location(&self) -> Location {
+        let mut lines = self.expansions.iter()
+            .map(|call| (&call.path, call.line))
+            .chain(Some((&self.path, self.line)));
+        let (path, line) = lines.next().unwrap();
+        Location {
+            path: path.clone(),
+            line,
+            expansions: self.expansions.iter().zip(lines)
+                .map(|(call, (path, line))| Expansion { name: call.name.clone(), path: path.clone(), line })
+                .collect(),
+        }
+    }
+
+    fn source_line(&self) -> SourceLine {
+        match &self.call_site {
+            Some(call_site) => call_site.clone(),
+            None => SourceLine {
+                path: self.path.clone(),
+                line: self.line,
+                column: self.column,
+                text: self.text.clone(),
+            },
+        }
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            location: self.location(),
+            message,
+        }
+    }
+
+    //errors from an included file already know where they happened
+    fn locate(&self, error: Box<dyn Error>) -> ParseError {
+        match error.downcast::<ParseError>() {
+            Ok(error) => *error,
+            Err(error) => self.error(error.to_string()),
+        }
+    }
+
+    fn export(&mut self, tokens: &[String]) -> Result<(), Box<dyn Error>> {
+        let name = operand(tokens, 1)?;
+        if let Some((file, _)) = self.exports.get(name) {
+            return Err(format!("{} is already exported by {}", name, self.files[*file].display()).into());
+        }
+        self.exports.insert(name.to_string(), (self.file, self.location()));
+        Ok(())
+    }
+
+    fn import(&mut self, tokens: &[String]) -> Result<(), Box<dyn Error>> {
+        let name = operand(tokens, 1)?;
+        if !is_identifier(name) {
+            return Err(format!("invalid name {}", name).into());
+        }
+        self.imports.insert((self.file, name.to_string()));
+        Ok(())
+    }
+
+    //exported labels keep their name, every other label is made unique to its file
+    fn symbol_name(&self, file: usize, label: &str) -> String {
+        match self.exports.get(label) {
+            Some((exporter, _)) if *exporter == file => label.to_string(),
+            _ => format!("{}%{}", label, file),
+        }
+    }
+
+    //resolves every reference to the label it means, following the imports of the file it is in
+    fn finish(mut self) -> Result<Module, ParseError> {
+        if let Some(unfinished) = &self.defining {
+            let mut location = self.location();
+            location.line = unfinished.line;
+            return Err(ParseError { location, message: format!("macro {} is missing {}", unfinished.name, ENDM) });
+        }
+        for (name, (file, location)) in &self.exports {
+            if !self.labels.contains_key(&(*file, name.clone())) {
+                return Err(ParseError { location: location.clone(), message: format!("exported label {} is never defined", name) });
+            }
+        }
+        let mut module = mem::take(&mut self.module);
+        for ((file, label), symbol) in &self.labels {
+            let name = self.symbol_name(*file, label);
+            let exported = &name == label;
+            module.symbols.insert(name, Symbol { exported, ..*symbol });
+        }
+        for (reference, (file, location)) in module.references.iter_mut().zip(&self.reference_sites) {
+            for label in reference.expression.symbols() {
+                if !self.labels.contains_key(&(*file, label.to_string())) && !self.imports.contains(&(*file, label.to_string())) {
+                    let hint = match self.exports.get(label) {
+                        Some(_) => format!(", import it with {} {}", IMPORT, label),
+                        None => String::new(),
+                    };
+                    let message = format!("undefined symbol {}{}", label, hint);
+                    return Err(ParseError { location: location.clone(), message });
+                }
+            }
+            //imports that no file here exports keep their name and are left for the linker
+            reference.expression.rename_symbols(&mut |label| {
+                if self.labels.contains_key(&(*file, label.to_string())) {
+                    self.symbol_name(*file, label)
+                } else {
+                    label.to_string()
+                }
+            });
+        }
+        Ok(module)
+    }
+
+    fn start_macro(&mut self, tokens: &[String]) -> Result<(), Box<dyn Error>> {
+        let name = operand(tokens, 1)?;
+        if !is_identifier(name) {
+            return Err(format!("invalid macro name {}", name).into());
+        }
+        if self.macros.contains_key(name) {
+            return Err(format!("macro {} is already defined", name).into());
+        }
+        let params = tokens[2..].to_vec();
+        if let Some(param) = params.iter().find(|param| !is_identifier(param)) {
+            return Err(format!("invalid macro parameter {}", param).into());
+        }
+        self.defining = Some(Macro {
+            name: name.to_string(),
+            params,
+            path: self.path.clone(),
+            line: self.line,
+            body: vec![],
+        });
+        Ok(())
+    }
+
+    //.equ and .reg names share one namespace with labels, so a name always means one thing
+    fn check_new_name(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        if !is_identifier(name) || register_number(name).is_some() {
+            return Err(format!("invalid name {}", name).into());
+        }
+        if self.constants.contains_key(name) || self.aliases.contains_key(name) || self.labels.contains_key(&(self.file, name.to_string())) {
+            return Err(format!("{} is already defined", name).into());
+        }
+        Ok(())
+    }
+
+    fn define_constant(&mut self, tokens: &[String]) -> Result<(), Box<dyn Error>> {
+        let name = operand(tokens, 1)?;
+        self.check_new_name(name)?;
+        let value = self.integer(operand(tokens, 2)?)?;
+        self.constants.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn define_alias(&mut self, tokens: &[String]) -> Result<(), Box<dyn Error>> {
+        let name = operand(tokens, 1)?;
+        self.check_new_name(name)?;
+        let register = self.register(tokens, 2)?;
+        self.aliases.insert(name.to_string(), register);
+        Ok(())
+    }
+
+    //r4, a bare 4, or a .reg alias
+    fn register(&self, tokens: &[String], index: usize) -> Result<u8, Box<dyn Error>> {
+        let token = operand(tokens, index)?;
+        let register = match register_number(token) {
+            Some(register) => register,
+            None if is_identifier(token) => *self.aliases.get(token)
+                .ok_or_else(|| format!("undefined register alias {}", token))?,
+            None => return Err(format!("invalid register {}", token).into()),
+        };
+        if register as usize >= REGISTER_COUNT {
+            return Err(format!("invalid register {}", token).into());
+        }
+        Ok(register)
+    }
+
+    //a 32 bit integer that may be written signed or unsigned, made of numbers, characters and .equ constants
+    fn integer(&self, token: &str) -> Result<u32, Box<dyn Error>> {
+        let expression = parse_expression(token, &self.constants)?;
+        if let Some(name) = expression.symbols().first() {
+            return Err(format!("undefined constant {}", name).into());
+        }
+        Ok(expression.evaluate(&|_| unreachable!("the expression has no labels"))?.constant)
+    }
+
+    fn define_scratch(&mut self, tokens: &[String]) -> Result<(), Box<dyn Error>> {
+        let scratch = (self.register(tokens, 1)?, self.register(tokens, 2)?);
+        if scratch.0 == scratch.1 {
+            return Err(format!("{} needs two different registers", SCRATCH).into());
+        }
+        self.scratch = scratch;
+        Ok(())
+    }
+
+    fn is_register(&self, token: &str) -> bool {
+        register_number(token).is_some() || self.aliases.contains_key(token)
+    }
+
+    fn push(&mut self, instruction: CI) {
+        self.module.code.push(instruction);
+        self.module.code_lines.push(self.source_line());
+    }
+
+    fn define(&mut self, label: &str) -> Result<(), Box<dyn Error>> {
+        if self.constants.contains_key(label) || self.aliases.contains_key(label) {
+            return Err(format!("{} is already defined", label).into());
+        }
+        if !is_identifier(label) || register_number(label).is_some() {
+            return Err(format!("invalid label name {}", label).into());
+        }
+        let offset = match self.section {
+            Section::Code => self.module.code.len(),
+            Section::Data => self.module.data.len(),
+        };
+        let symbol = Symbol { section: self.section, offset, exported: false };
+        if self.labels.insert((self.file, label.to_string()), symbol).is_some() {
+            return Err(format!("label {} is defined twice", label).into());
+        }
+        Ok(())
+    }
+
+    //an integer, or an expression using labels whose value is filled in by the assembler
+    fn address(&mut self, token: &str) -> Result<u32, Box<dyn Error>> {
+        let expression = parse_expression(token, &self.constants)?;
+        if expression.symbols().is_empty() {
+            return self.integer(token);
+        }
+        self.reference(expression);
+        Ok(0)
+    }
+
+    //the next instruction pushed is a LOAD of the value of expression
+    fn reference(&mut self, expression: Expression) {
+        self.module.references.push(Reference {
+            at: self.module.code.len(),
+            expression,
+        });
+        self.reference_sites.push((self.file, self.location()));
+    }
+
+    //labels the assembler makes for itself have a . in their name, so they can't clash with any written in a file
+    fn internal_label(&mut self, section: Section, offset: usize, name: &str) {
+        let symbol = Symbol { section, offset, exported: false };
+        self.labels.insert((self.file, name.to_string()), symbol);
+    }
+}
+
 impl Parser {
     pub fn new() -> Self {
         Self {}
     }
 
-    pub fn parse_ci_asm(&self, path: &str) -> Result<Vec<CI>, Box<dyn Error>> {
-        let mut byte_code = String::new();
+    pub fn parse_ci_asm(&self, path: &str) -> Result<Module, Box<dyn Error>> {
+        let mut source = String::new();
         OpenOptions::new()
             .read(true)
-            .open(path)
-            .unwrap()
-            .read_to_string(&mut byte_code)
-            .unwrap();
-        let mut ci_code = vec![];
-        for line in byte_code.lines() {
-            let split = line.split_ascii_whitespace().collect::<Vec<_>>();
-            let val = match split[0] {
-                LOAD => {
-                    CI::LOAD(split[1].parse::<u8>()?, split[2].parse::<u32>()?)
-                }
-                LOADI => {
-                    let val = split[2].parse::<i32>()?;
-                    let val_u32 = unsafe { std::mem::transmute::<i32, u32>(val) };
-                    CI::LOAD(split[1].parse::<u8>()?, val_u32)
-                }
-                LOADF => {
-                    let val = split[2].parse::<f32>()?;
-                    let val_u32 = unsafe { std::mem::transmute::<f32, u32>(val) };
-                    CI::LOAD(split[1].parse::<u8>()?, val_u32)
-                }
-                PRINT => {
-                    CI::PRINT(split[1].parse::<u8>()?)
-                }
-                INC => {
-                    CI::INC(split[1].parse::<u8>()?)
-                }
-                DEC => {
-                    CI::DEC(split[1].parse::<u8>()?)
-                }
-                ADD => {
-                    CI::ADD(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                SUB => {
-                    CI::SUB(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                MUL => {
-                    CI::MUL(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                DIV => {
-                    CI::DIV(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                MOD => {
-                    CI::MOD(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                FADD => {
-                    CI::FADD(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                FSUB => {
-                    CI::FSUB(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                FMUL => {
-                    CI::FMUL(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                FDIV => {
-                    CI::FDIV(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                HLT => {
-                    CI::HLT
-                }
-                JMP => {
-                    CI::JMP(split[1].parse::<u8>()?)
-                }
-                JMPB => {
-                    CI::JMPB(split[1].parse::<u8>()?)
-                }
-                JMPF => {
-                    CI::JMPF(split[1].parse::<u8>()?)
-                }
-                EQ => {
-                    CI::EQ(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                NE => {
-                    CI::NE(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                GT => {
-                    CI::GT(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                LT => {
-                    CI::LT(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                GTQ => {
-                    CI::GTQ(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                LTQ => {
-                    CI::LTQ(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                FEQ => {
-                    CI::FEQ(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                FNE => {
-                    CI::FNE(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                FGT => {
-                    CI::FGT(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                FLT => {
-                    CI::FLT(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                FGTQ => {
-                    CI::FGTQ(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                FLTQ => {
-                    CI::FLTQ(split[1].parse::<u8>()?, split[2].parse::<u8>()?, split[3].parse::<u8>()?)
-                }
-                PUSH => {
-                    CI::PUSH(split[1].parse::<u8>()?)
-                }
-                SPUSH => {
-                    CI::SPUSH(split[1].parse::<u8>()?)
-                }
-                POP => {
-                    CI::POP(split[1].parse::<u8>()?)
-                }
-                SPOP => {
-                    CI::SPOP(split[1].parse::<u8>()?)
-                }
-                SREAD => {
-                    CI::SREAD(split[1].parse::<u8>()?, split[2].parse::<u8>()?)
-                }
-                SWRITE => {
-                    CI::SWRITE(split[1].parse::<u8>()?, split[2].parse::<u8>()?)
-                }
-                MOV => {
-                    CI::MOV(split[1].parse::<u8>()?, split[2].parse::<u8>()?)
-                }
-                JEQ => {
-                    CI::JEQ(split[1].parse::<u8>()?, split[2].parse::<u8>()?)
-                }
-                JNE => {
-                    CI::JNE(split[1].parse::<u8>()?, split[2].parse::<u8>()?)
+            .open(path)?
+            .read_to_string(&mut source)?;
+        self.parse_source(path, &source)
+    }
+
+    pub fn parse_source(&self, path: &str, source: &str) -> Result<Module, Box<dyn Error>> {
+        let mut state = ParseState::new(path);
+        self.parse_lines(&mut state, source)?;
+        Ok(state.finish()?)
+    }
+
+    fn parse_lines(&self, state: &mut ParseState, source: &str) -> Result<(), ParseError> {
+        for (index, line) in source.lines().enumerate() {
+            state.line = index + 1;
+            state.column = instruction_column(line);
+            tokenize(line)
+                .and_then(|tokens| self.parse_tokens(state, tokens))
+                .map_err(|error| state.locate(error))?;
+        }
+        Ok(())
+    }
+
+    //parses a file in place of the .include line, unless it has been included before
+    fn include(&self, state: &mut ParseState, tokens: &[String]) -> Result<(), Box<dyn Error>> {
+        let name = String::from_utf8(unescape(operand(tokens, 1)?)?)?;
+        let path = Path::new(&state.path).parent().unwrap_or_else(|| Path::new("")).join(name);
+        let file = fs::canonicalize(&path)
+            .map_err(|error| format!("can't include {}: {}", path.display(), error))?;
+        if let Some(start) = state.include_stack.iter().position(|&index| state.files[index] == file) {
+            let cycle = state.include_stack[start..].iter()
+                .map(|&index| state.files[index].display().to_string())
+                .chain(Some(file.display().to_string()))
+                .collect::<Vec<_>>();
+            return Err(format!("include cycle: {}", cycle.join(" -> ")).into());
+        }
+        if state.files.contains(&file) {
+            return Ok(());
+        }
+        let source = fs::read_to_string(&path)?;
+        state.files.push(file);
+        let index = state.files.len() - 1;
+        state.include_stack.push(index);
+        let including_path = mem::replace(&mut state.path, path.display().to_string());
+        let including_line = state.line;
+        let including_section = mem::replace(&mut state.section, Section::Code);
+        let including_file = mem::replace(&mut state.file, index);
+        self.parse_lines(state, &source)?;
+        if let Some(unfinished) = &state.defining {
+            state.line = unfinished.line;
+            return Err(format!("macro {} is missing {}", unfinished.name, ENDM).into());
+        }
+        state.path = including_path;
+        state.line = including_line;
+        state.section = including_section;
+        state.file = including_file;
+        state.include_stack.pop();
+        Ok(())
+    }
+
+    fn parse_tokens(&self, state: &mut ParseState, mut tokens: Vec<String>) -> Result<(), Box<dyn Error>> {
+        if let Some(definition) = &mut state.defining {
+            match tokens.first().map(String::as_str) {
+                Some(ENDM) => {
+                    let definition = state.defining.take().unwrap();
+                    state.macros.insert(definition.name.clone(), Rc::new(definition));
                 }
-                MALLOC => {
-                    CI::MALLOC(split[1].parse::<u8>()?, split[2].parse::<u8>()?)
+                Some(MACRO) => return Err(format!("{} inside of macro {}", MACRO, definition.name).into()),
+                _ => definition.body.push((state.line, state.column, tokens)),
+            }
+            return Ok(());
+        }
+        if let Some(label) = tokens.first().and_then(|token| token.strip_suffix(':')) {
+            state.define(label)?;
+            tokens.remove(0);
+        }
+        state.text = tokens.join(" ");
+        let first = match tokens.first() {
+            Some(first) => first.as_str(),
+            None => return Ok(()),
+        };
+        match first {
+            TEXT_SECTION => state.section = Section::Code,
+            DATA_SECTION => state.section = Section::Data,
+            EQU => state.define_constant(&tokens)?,
+            REG => state.define_alias(&tokens)?,
+            MACRO => state.start_macro(&tokens)?,
+            INCLUDE => self.include(state, &tokens)?,
+            EXPORT => state.export(&tokens)?,
+            IMPORT => state.import(&tokens)?,
+            SCRATCH => state.define_scratch(&tokens)?,
+            ENDM => return Err(format!("{} without {}", ENDM, MACRO).into()),
+            WORD | FLOAT | STRING => {
+                if state.section != Section::Data {
+                    return Err(format!("{} is only allowed in the .data section", first).into());
                 }
-                FREE => {
-                    CI::FREE(split[1].parse::<u8>()?)
+                self.parse_data(state, &tokens)?;
+            }
+            _ if state.macros.contains_key(first) => {
+                let definition = state.macros[first].clone();
+                self.expand(state, &definition, &tokens[1..])?;
+            }
+            _ => {
+                if state.section != Section::Code {
+                    return Err(format!("instruction {} is only allowed in the .text section", first).into());
                 }
-                FTOI => {
-                    CI::FTOI(split[1].parse::<u8>()?, split[2].parse::<u8>()?)
+                if !self.parse_pseudo_instruction(state, &tokens)? {
+                    let instruction = self.parse_instruction(state, &tokens)?;
+                    state.push(instruction);
                 }
-                ITOF => {
-                    CI::ITOF(split[1].parse::<u8>()?, split[2].parse::<u8>()?)
+            }
+        }
+        Ok(())
+    }
+
+    fn expand(&self, state: &mut ParseState, definition: &Macro, args: &[String]) -> Result<(), Box<dyn Error>> {
+        if state.expansions.len() >= MAX_EXPANSION_DEPTH {
+            return Err(format!("macro {} expands itself too deeply", definition.name).into());
+        }
+        definition.check_args(args)?;
+        let id = state.expansion_count;
+        let locals = definition.locals();
+        state.expansion_count += 1;
+        if state.expansions.is_empty() {
+            state.call_site = Some(state.source_line());
+        }
+        state.expansions.push(Expansion {
+            name: definition.name.clone(),
+            path: state.path.clone(),
+            line: state.line,
+        });
+        let call_path = mem::replace(&mut state.path, definition.path.clone());
+        let call_line = state.line;
+        for (line, column, tokens) in &definition.body {
+            state.line = *line;
+            state.column = *column;
+            let tokens = definition.expand_line(tokens, args, &locals, id)?;
+            self.parse_tokens(state, tokens)?;
+        }
+        //on errors this is skipped on purpose, so the error reports where in the expansion it happened
+        state.path = call_path;
+        state.line = call_line;
+        state.expansions.pop();
+        if state.expansions.is_empty() {
+            state.call_site = None;
+        }
+        Ok(())
+    }
+
+    fn parse_data(&self, state: &mut ParseState, tokens: &[String]) -> Result<(), Box<dyn Error>> {
+        let start = state.module.data.len();
+        for token in &tokens[1..] {
+            let bytes = match tokens[0].as_str() {
+                WORD => state.integer(token)?.to_le_bytes().to_vec(),
+                FLOAT => number::<f32>(token)?.to_le_bytes().to_vec(),
+                _ => {
+                    let mut bytes = unescape(token)?;
+                    bytes.push(0);
+                    bytes
                 }
-                I32 => {
-                    CI::I32(split[1].parse::<u8>()?, split[2].parse::<u8>()?)
+            };
+            state.module.data.extend(bytes);
+        }
+        if state.module.data.len() > start {
+            state.module.data_lines.push((start, state.source_line()));
+        }
+        Ok(())
+    }
+
+    //expands a pseudo-instruction into the instructions it stands for, tells if tokens was one
+    fn parse_pseudo_instruction(&self, state: &mut ParseState, tokens: &[String]) -> Result<bool, Box<dyn Error>> {
+        let (scratch, second_scratch) = state.scratch;
+        match tokens[0].as_str() {
+            //jmp to a register is a real instruction
+            JMP if !state.is_register(operand(tokens, 1)?) => {
+                let target = state.address(operand(tokens, 1)?)?;
+                state.push(CI::LOAD(scratch, target));
+                state.push(CI::JMP(scratch));
+            }
+            BEQ | BNE | BLT | BGT | BLE | BGE => {
+                let left = state.register(tokens, 1)?;
+                let right = state.register(tokens, 2)?;
+                let compare = match tokens[0].as_str() {
+                    BEQ => CI::EQ(left, right, scratch),
+                    BNE => CI::NE(left, right, scratch),
+                    BLT => CI::LT(left, right, scratch),
+                    BGT => CI::GT(left, right, scratch),
+                    BLE => CI::LTQ(left, right, scratch),
+                    _ => CI::GTQ(left, right, scratch),
+                };
+                state.push(compare);
+                let target = state.address(operand(tokens, 3)?)?;
+                state.push(CI::LOAD(second_scratch, target));
+                state.push(CI::JEQ(scratch, second_scratch));
+            }
+            //the return address goes on the stack, ret pops it
+            CALL => {
+                let back = format!("return.{}", state.call_count);
+                state.call_count += 1;
+                state.reference(Expression::Symbol(back.clone()));
+                state.push(CI::LOAD(scratch, 0));
+                state.push(CI::PUSH(scratch));
+                let target = state.address(operand(tokens, 1)?)?;
+                state.push(CI::LOAD(scratch, target));
+                state.push(CI::JMP(scratch));
+                state.internal_label(Section::Code, state.module.code.len(), &back);
+            }
+            RET => {
+                state.push(CI::POP(scratch));
+                state.push(CI::JMP(scratch));
+            }
+            NOP => state.push(CI::MOV(scratch, scratch)),
+            CLR => {
+                let register = state.register(tokens, 1)?;
+                state.push(CI::SUB(register, register, register));
+            }
+            //logical not, 0 becomes 1 and everything else 0
+            NOT => {
+                let register = state.register(tokens, 1)?;
+                if register == scratch {
+                    return Err(format!("{} can't be used on its scratch register r{}", NOT, scratch).into());
                 }
-                F32 => {
-                    CI::F32(split[1].parse::<u8>()?, split[2].parse::<u8>()?)
+                state.push(CI::LOAD(scratch, 0));
+                state.push(CI::EQ(register, scratch, register));
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn parse_instruction(&self, state: &mut ParseState, tokens: &[String]) -> Result<CI, Box<dyn Error>> {
+        let instruction = match tokens[0].as_str() {
+            LOAD => {
+                CI::LOAD(state.register(tokens, 1)?, state.address(operand(tokens, 2)?)?)
+            }
+            LOADI => {
+                CI::LOAD(state.register(tokens, 1)?, state.address(operand(tokens, 2)?)?)
+            }
+            LOADF => {
+                let val = number::<f32>(operand(tokens, 2)?)?;
+                let val_u32 = val.to_bits();
+                CI::LOAD(state.register(tokens, 1)?, val_u32)
+            }
+            PRINT => {
+                CI::PRINT(state.register(tokens, 1)?)
+            }
+            PRINTS => {
+                CI::PRINTS(state.register(tokens, 1)?)
+            }
+            INC => {
+                CI::INC(state.register(tokens, 1)?)
+            }
+            DEC => {
+                CI::DEC(state.register(tokens, 1)?)
+            }
+            ADD => {
+                CI::ADD(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            SUB => {
+                CI::SUB(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            MUL => {
+                CI::MUL(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            DIV => {
+                CI::DIV(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            MOD => {
+                CI::MOD(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            FADD => {
+                CI::FADD(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            FSUB => {
+                CI::FSUB(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            FMUL => {
+                CI::FMUL(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            FDIV => {
+                CI::FDIV(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            HLT => {
+                CI::HLT
+            }
+            EXIT => {
+                CI::EXIT(state.register(tokens, 1)?)
+            }
+            JMP => {
+                CI::JMP(state.register(tokens, 1)?)
+            }
+            JMPB => {
+                CI::JMPB(state.register(tokens, 1)?)
+            }
+            JMPF => {
+                CI::JMPF(state.register(tokens, 1)?)
+            }
+            EQ => {
+                CI::EQ(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            NE => {
+                CI::NE(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            GT => {
+                CI::GT(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            LT => {
+                CI::LT(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            GTQ => {
+                CI::GTQ(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            LTQ => {
+                CI::LTQ(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            FEQ => {
+                CI::FEQ(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            FNE => {
+                CI::FNE(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            FGT => {
+                CI::FGT(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            FLT => {
+                CI::FLT(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            FGTQ => {
+                CI::FGTQ(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            FLTQ => {
+                CI::FLTQ(state.register(tokens, 1)?, state.register(tokens, 2)?, state.register(tokens, 3)?)
+            }
+            PUSH => {
+                CI::PUSH(state.register(tokens, 1)?)
+            }
+            SPUSH => {
+                CI::SPUSH(state.register(tokens, 1)?)
+            }
+            POP => {
+                CI::POP(state.register(tokens, 1)?)
+            }
+            SPOP => {
+                CI::SPOP(state.register(tokens, 1)?)
+            }
+            SREAD => {
+                CI::SREAD(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            SWRITE => {
+                CI::SWRITE(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            READ | HREAD | LOAD32 => {
+                CI::READ(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            WRITE | STORE32 => {
+                CI::WRITE(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            //hwrite takes the address first, the way it always has
+            HWRITE => {
+                CI::WRITE(state.register(tokens, 2)?, state.register(tokens, 1)?)
+            }
+            LOAD8U => {
+                CI::LOAD8U(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            LOAD8S => {
+                CI::LOAD8S(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            LOAD16U => {
+                CI::LOAD16U(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            LOAD16S => {
+                CI::LOAD16S(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            STORE8 => {
+                CI::STORE8(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            STORE16 => {
+                CI::STORE16(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            MOV => {
+                CI::MOV(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            JEQ => {
+                CI::JEQ(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            JNE => {
+                CI::JNE(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            MALLOC => {
+                CI::MALLOC(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            FREE => {
+                CI::FREE(state.register(tokens, 1)?)
+            }
+            FTOI => {
+                CI::FTOI(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            ITOF => {
+                CI::ITOF(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            I32 => {
+                CI::I32(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            F32 => {
+                CI::F32(state.register(tokens, 1)?, state.register(tokens, 2)?)
+            }
+            _ => {
+                return Err(format!("illegal CVM instruction {}", tokens[0]).into());
+            }
+        };
+        Ok(instruction)
+    }
+}
+
+fn is_identifier(token: &str) -> bool {
+    let mut chars = token.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            //@ only shows up in the names macro expansions give their labels
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '@')
+        }
+        _ => false,
+    }
+}
+
+//1 based column of the first token after the label, if the line has one
+fn instruction_column(line: &str) -> usize {
+    let mut rest = line.trim_start();
+    if let Some(first) = rest.split_whitespace().next().filter(|first| first.ends_with(':')) {
+        rest = rest[first.len()..].trim_start();
+    }
+    line.len() - rest.len() + 1
+}
+
+fn operand(tokens: &[String], index: usize) -> Result<&str, Box<dyn Error>> {
+    match tokens.get(index) {
+        Some(token) => Ok(token),
+        None => Err(format!("{} is missing operand {}", tokens[0], index).into()),
+    }
+}
+
+//the number of a register written as r4 or as a bare 4
+fn register_number(token: &str) -> Option<u8> {
+    token.strip_prefix('r').unwrap_or(token).parse::<u8>().ok()
+}
+
+fn number<T: FromStr>(token: &str) -> Result<T, Box<dyn Error>> {
+    token.parse::<T>().map_err(|_| format!("invalid number {}", token).into())
+}
+
+//splits a line into tokens, keeping quoted strings in one piece and dropping # comments
+fn tokenize(line: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut chars = line.chars();
+    //spaces inside parentheses don't end a token, so (SIZE * 4) is one operand
+    let mut depth = 0usize;
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => break,
+            '"' | '\'' => {
+                token.push(c);
+                loop {
+                    match chars.next() {
+                        Some('\\') => {
+                            token.push('\\');
+                            token.extend(chars.next());
+                        }
+                        Some(end) if end == c => break,
+                        Some(c) => token.push(c),
+                        None if c == '"' => return Err("unterminated string".into()),
+                        None => return Err("unterminated character literal".into()),
+                    }
                 }
-                _ => {
-                    panic!("illegal CVM instruction")
+                token.push(c);
+            }
+            '(' | ')' => {
+                depth = if c == '(' { depth + 1 } else { depth.saturating_sub(1) };
+                token.push(c);
+            }
+            c if c.is_whitespace() && depth > 0 => token.push(c),
+            c if c.is_whitespace() => {
+                if !token.is_empty() {
+                    tokens.push(mem::take(&mut token));
                 }
-            };
-            ci_code.push(val);
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    Ok(join_operators(tokens))
+}
+
+//Puts expressions written with spaces, like label + 8, back into one token. A
+//- or + directly in front of a number is a sign, so .word 1 -1 is still two words.
+fn join_operators(tokens: Vec<String>) -> Vec<String> {
+    const OPERATORS: &[char] = &['+', '-', '*', '/', '%', '&', '|', '^', '<', '>', '~'];
+    const BINARY_OPERATORS: &[char] = &['*', '/', '%', '&', '|', '^', '<', '>'];
+    let mut joined: Vec<String> = vec![];
+    for token in tokens {
+        let continues = joined.last().is_some_and(|last| last.ends_with(OPERATORS))
+            || token.starts_with(BINARY_OPERATORS)
+            || token == "+"
+            || token == "-";
+        match joined.last_mut() {
+            Some(last) if continues => {
+                last.push(' ');
+                last.push_str(&token);
+            }
+            _ => joined.push(token),
+        }
+    }
+    joined
+}
+
+//the bytes of a quoted string token, with escape sequences replaced
+fn unescape(token: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let inner = token
+        .strip_prefix('"')
+        .and_then(|token| token.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a quoted string, found {}", token))?;
+    let mut bytes = vec![];
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' { escaped(chars.next())? } else { c };
+        let mut buffer = [0u8; 4];
+        bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+    }
+    Ok(bytes)
+}
+
+//the character an escape sequence stands for, given what follows the backslash
+pub fn escaped(c: Option<char>) -> Result<char, Box<dyn Error>> {
+    match c {
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some('r') => Ok('\r'),
+        Some('0') => Ok('\0'),
+        Some('\\') => Ok('\\'),
+        Some('"') => Ok('"'),
+        Some('\'') => Ok('\''),
+        other => Err(format!("invalid escape sequence \\{}", other.unwrap_or(' ')).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet_assembler::assembler::CarpetAssembler;
+    use std::env;
+    use std::process;
+
+    const MACROS: &str = "\
+        .macro twice r\n\
+        inc \\r\n\
+        inc \\r\n\
+        .endm\n\
+        .macro four r\n\
+        twice \\r\n\
+        twice \\r\n\
+        .endm\n\
+        loadi r1 0\n\
+        twice r1\n\
+        four r2\n\
+        hlt\n";
+
+    fn error(source: &str) -> String {
+        Parser::new().parse_source("macros.cbc", source).unwrap_err().to_string()
+    }
+
+    //writes the files to a directory of their own and parses the first one
+    fn parse_files(name: &str, files: &[(&str, &str)]) -> Result<Module, String> {
+        let directory = env::temp_dir().join(format!("carpet-{}-{}", name, process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        //include cycles are reported with the canonical paths
+        let directory = fs::canonicalize(directory).unwrap();
+        for (file, source) in files {
+            fs::write(directory.join(file), source).unwrap();
         }
-        Ok(ci_code)
+        let path = directory.join(files[0].0).display().to_string();
+        let module = Parser::new().parse_ci_asm(&path).map_err(|error| error.to_string());
+        fs::remove_dir_all(&directory).unwrap();
+        module.map_err(|error| error.replace(&format!("{}/", directory.display()), ""))
+    }
+
+    #[test]
+    fn macro_instructions_are_at_the_call() {
+        let module = Parser::new().parse_source("macros.cbc", MACROS).unwrap();
+        let lines: Vec<(usize, &str)> = module.code_lines.iter().map(|line| (line.line, line.text.as_str())).collect();
+        assert_eq!(lines, vec![
+            (9, "loadi r1 0"),
+            (10, "twice r1"),
+            (10, "twice r1"),
+            (11, "four r2"),
+            (11, "four r2"),
+            (11, "four r2"),
+            (11, "four r2"),
+            (12, "hlt"),
+        ]);
+        //so that is where the debug info puts them
+        let program = CarpetAssembler::new().assemble(module).unwrap();
+        assert_eq!(program.debug_info.lookup(8).unwrap().entry.line, 10);
+        assert_eq!(program.debug_info.lookup(28).unwrap().entry.line, 11);
+    }
+
+    #[test]
+    fn macro_errors_name_the_call_and_the_macro_lines() {
+        assert_eq!(
+            error(".macro bad r\ninc \\r\n.endm\n.macro outer r\nbad \\r\n.endm\nhlt\nouter r99\n"),
+            "macros.cbc:8: invalid register r99\n\
+             macros.cbc:5: note: in macro outer\n\
+             macros.cbc:2: note: in macro bad"
+        );
+        //labels are resolved after parsing, they still know where they were used
+        assert_eq!(error(".macro bad\njmp nowhere\n.endm\nbad\n"), "macros.cbc:4: undefined symbol nowhere\nmacros.cbc:2: note: in macro bad");
+        assert_eq!(error(".macro bad r\ninc \\r\n.endm\nbad r1 r2\n"), "macros.cbc:4: macro bad takes 1 arguments but 2 were given");
+        assert_eq!(error("hlt\n.macro open\ninc r1\n"), "macros.cbc:2: macro open is missing .endm");
+        let recursive = error(".macro again\nagain\n.endm\nagain\n");
+        assert!(recursive.starts_with("macros.cbc:4: macro again expands itself too deeply\n"));
+        assert_eq!(recursive.lines().count(), MAX_EXPANSION_DEPTH + 1);
+    }
+
+    #[test]
+    fn macros_from_included_files() {
+        let module = parse_files("macros", &[
+            ("main.cbc", ".include \"defs.cbc\"\nloadi r1 0\nbump r1\nhlt\n"),
+            ("defs.cbc", "# bump\n.macro bump r\ninc \\r\n.endm\n"),
+        ]).unwrap();
+        let lines: Vec<(&str, usize)> = module.code_lines.iter()
+            .map(|line| (Path::new(&line.path).file_name().unwrap().to_str().unwrap(), line.line))
+            .collect();
+        assert_eq!(lines, vec![("main.cbc", 2), ("main.cbc", 3), ("main.cbc", 4)]);
+        let error = parse_files("macro-error", &[
+            ("main.cbc", ".include \"defs.cbc\"\nbump r99\n"),
+            ("defs.cbc", ".macro bump r\ninc \\r\n.endm\n"),
+        ]).unwrap_err();
+        assert_eq!(error, "main.cbc:2: invalid register r99\ndefs.cbc:2: note: in macro bump");
+    }
+
+    #[test]
+    fn include_cycles() {
+        let error = parse_files("cycle", &[
+            ("main.cbc", ".include \"a.cbc\"\nhlt\n"),
+            ("a.cbc", "inc r1\n.include \"b.cbc\"\n"),
+            ("b.cbc", ".include \"a.cbc\"\n"),
+        ]).unwrap_err();
+        assert_eq!(error, "b.cbc:1: include cycle: a.cbc -> b.cbc -> a.cbc");
+        let error = parse_files("self", &[("main.cbc", "hlt\n.include \"main.cbc\"\n")]).unwrap_err();
+        assert_eq!(error, "main.cbc:2: include cycle: main.cbc -> main.cbc");
+        //included twice but not through itself, the second time is skipped
+        let module = parse_files("twice", &[
+            ("main.cbc", ".include \"a.cbc\"\n.include \"a.cbc\"\nhlt\n"),
+            ("a.cbc", "inc r1\n"),
+        ]).unwrap();
+        assert_eq!(module.code.len(), 2);
+    }
+
+    #[test]
+    fn errors_in_included_files() {
+        let error = parse_files("inner", &[("main.cbc", ".include \"a.cbc\"\nhlt\n"), ("a.cbc", "inc r1\ninc r99\n")]).unwrap_err();
+        assert_eq!(error, "a.cbc:2: invalid register r99");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,137 @@
+use crate::carpet::cvm::CVM;
+use crate::carpet_assembler::assembler::CarpetAssembler;
+use crate::parser::number::parse_unsigned;
+use crate::parser::parse::{Parser, ENDM, MACRO};
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+
+//the file name lines typed into the repl are reported under
+const REPL_PATH: &str = "repl";
+const HELP: &str = "\
+lines of assembly run as soon as they are entered, labels, .equ constants and macros
+from earlier lines can be used, a .macro is run once its .endm is entered
+:regs           shows the registers
+:stack          shows the values on the stack, from the bottom up
+:heap ptr len   shows the len bytes at address ptr
+:reset          starts over with an empty program and a new CVM
+:help           shows this
+:quit           leaves the repl, so does end of input";
+
+//carpet repl: everything typed so far is assembled again for every new line, and
+//the CVM runs the code that line added, keeping its registers and memory
+pub fn repl() -> Result<(), Box<dyn Error>> {
+    let parser = Parser::new();
+    let carpet_assembler = CarpetAssembler::new();
+    let mut cvm = CVM::new();
+    //the lines that assembled, and the code they assembled to
+    let mut source = String::new();
+    let mut code = vec![];
+    //the lines of a macro definition that isn't finished yet
+    let mut pending = String::new();
+    let mut in_macro = false;
+    println!("carpet repl, :help lists the commands");
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}", if pending.is_empty() { "> " } else { "... " });
+        io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let first = line.split_whitespace().next().unwrap_or_default();
+        if pending.is_empty() && first.starts_with(':') {
+            let arguments: Vec<&str> = line.split_whitespace().skip(1).collect();
+            match first {
+                ":regs" => print_registers(cvm.registers()),
+                ":stack" => print_stack(&cvm.stack()),
+                ":heap" => match memory_range(&arguments) {
+                    Ok((address, len)) => match cvm.read_memory(address, len) {
+                        Ok(bytes) => print_bytes(address, &bytes),
+                        Err(trap) => eprintln!("CVM {}", trap),
+                    },
+                    Err(error) => eprintln!("{}", error),
+                },
+                ":reset" => {
+                    cvm = CVM::new();
+                    source.clear();
+                    code.clear();
+                }
+                ":help" => println!("{}", HELP),
+                ":quit" => break,
+                _ => eprintln!("unknown command {}, :help lists the commands", first),
+            }
+            continue;
+        }
+        pending.push_str(&line);
+        pending.push('\n');
+        if first == MACRO {
+            in_macro = true;
+        } else if first == ENDM {
+            in_macro = false;
+        }
+        if in_macro {
+            continue;
+        }
+        let candidate = format!("{}{}", source, pending);
+        pending.clear();
+        let program = match parser.parse_source(REPL_PATH, &candidate).and_then(|module| carpet_assembler.assemble(module)) {
+            Ok(program) => program,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            }
+        };
+        //code can only be added at the end, what already ran stays the same
+        if !program.code.starts_with(&code) {
+            eprintln!("{} would change code that already ran, use :reset to start over", line.trim());
+            continue;
+        }
+        source = candidate;
+        code = program.code.clone();
+        cvm.extend_program(program);
+        if let Err(error) = cvm.run() {
+            eprintln!("{}", error);
+        }
+    }
+    println!();
+    Ok(())
+}
+
+//ptr and len as decimal or 0x hex numbers
+pub fn memory_range(arguments: &[&str]) -> Result<(usize, usize), Box<dyn Error>> {
+    match arguments {
+        [address, len] => Ok((
+            parse_unsigned(address).ok_or_else(|| format!("invalid address {}", address))?,
+            parse_unsigned(len).ok_or_else(|| format!("invalid length {}", len))?,
+        )),
+        _ => Err("an address and a length are needed".into()),
+    }
+}
+
+pub fn print_registers(registers: &[u32]) {
+    for (row, chunk) in registers.chunks(4).enumerate() {
+        let columns: Vec<String> = chunk.iter().enumerate()
+            .map(|(column, value)| format!("{:>4} = {:<12}", format!("r{}", row * 4 + column), *value as i32))
+            .collect();
+        println!("{}", columns.join(" ").trim_end());
+    }
+}
+
+pub fn print_stack(stack: &[u32]) {
+    if stack.is_empty() {
+        println!("the stack is empty");
+    }
+    for (index, value) in stack.iter().enumerate() {
+        println!("{:>4}: {} ({:#x})", index, *value as i32, value);
+    }
+}
+
+//16 bytes a row, with the address of the first one
+pub fn print_bytes(address: usize, bytes: &[u8]) {
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = chunk.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+        println!("{:#08x}  {:<48} {}", address + row * 16, hex.join(" "), text);
+    }
+}
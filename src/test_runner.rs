@@ -0,0 +1,190 @@
+use crate::carpet::cvm::{CVM, REGISTER_COUNT};
+use crate::carpet::program::Program;
+use crate::parser::number::parse_word;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+
+//the comments at the top of a test program that say what it should do, "# expect stdout: hello\n"
+const EXPECT: &str = "expect ";
+const SOURCE_EXTENSION: &str = "cbc";
+
+//What a test program declares. A program that declares nothing isn't a test,
+//one that declares no error and no exit code has to exit with 0.
+#[derive(Debug, Default)]
+struct Expectations {
+    stdout: Option<String>,
+    exit_code: Option<i32>,
+    registers: Vec<(usize, u32)>,
+    //a part of the message of the trap the program has to stop with
+    error: Option<String>,
+}
+
+//carpet test: runs every program that declares expectations, each on a CVM of its own
+//from new_cvm, and tells if all of them passed
+pub fn run_tests(
+    paths: &[String],
+    load: impl Fn(&str) -> Result<Program, Box<dyn Error>>,
+    new_cvm: impl Fn() -> CVM,
+) -> Result<bool, Box<dyn Error>> {
+    let mut files = vec![];
+    for path in paths {
+        collect(Path::new(path), &mut files)?;
+    }
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for path in &files {
+        let source = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+        let problems = match (expectations(&source), load(path)) {
+            (Ok(None), _) => {
+                skipped += 1;
+                continue;
+            }
+            (Err(error), _) => vec![format!("header: {}", error)],
+            (_, Err(error)) => vec![format!("doesn't assemble: {}", error)],
+            (Ok(Some(expectations)), Ok(program)) => {
+                let mut cvm = new_cvm();
+                cvm.new_program(program);
+                let result = cvm.run().map_err(|error| error.to_string());
+                check(&expectations, result, &String::from_utf8_lossy(&cvm.take_output()), cvm.registers())
+            }
+        };
+        if problems.is_empty() {
+            passed += 1;
+            println!("ok   {}", path);
+        } else {
+            failed += 1;
+            println!("FAIL {}", path);
+            for problem in problems {
+                println!("     {}", problem);
+            }
+        }
+    }
+    println!("{} passed, {} failed, {} skipped", passed, failed, skipped);
+    Ok(failed == 0)
+}
+
+//the .cbc files in a directory and the ones below it, in order, a file is taken as it is
+fn collect(path: &Path, files: &mut Vec<String>) -> Result<(), Box<dyn Error>> {
+    if !path.is_dir() {
+        files.push(path.display().to_string());
+        return Ok(());
+    }
+    let mut entries: Vec<_> = fs::read_dir(path)?.map(|entry| entry.map(|entry| entry.path())).collect::<Result<_, _>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() || entry.extension() == Some(OsStr::new(SOURCE_EXTENSION)) {
+            collect(&entry, files)?;
+        }
+    }
+    Ok(())
+}
+
+//the expect lines in the comments before the first line of code, None if there are none
+fn expectations(source: &str) -> Result<Option<Expectations>, Box<dyn Error>> {
+    let mut expectations = Expectations::default();
+    let mut found = false;
+    for line in source.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let comment = match line.strip_prefix('#') {
+            Some(comment) => comment.trim_start(),
+            None => break,
+        };
+        let (key, value) = match comment.strip_prefix(EXPECT).and_then(|expectation| expectation.split_once(':')) {
+            Some((key, value)) => (key.trim(), value.strip_prefix(' ').unwrap_or(value)),
+            None => continue,
+        };
+        found = true;
+        match key {
+            //stdout can take several lines, which are put together
+            "stdout" => expectations.stdout.get_or_insert_with(String::new).push_str(&unescape(value)?),
+            "exit" => expectations.exit_code = Some(value.trim().parse().map_err(|_| format!("invalid exit code {}", value))?),
+            "error" => expectations.error = Some(value.trim().to_string()),
+            _ => {
+                let register = key.strip_prefix('r')
+                    .and_then(|register| register.parse::<usize>().ok())
+                    .filter(|&register| register < REGISTER_COUNT)
+                    .ok_or_else(|| format!("unknown expectation {}", key))?;
+                let value = value.trim();
+                let number = parse_word(value).ok_or_else(|| format!("invalid register value {}", value))?;
+                expectations.registers.push((register, number));
+            }
+        }
+    }
+    Ok(found.then_some(expectations))
+}
+
+//what didn't go the way the expectations say
+fn check(expectations: &Expectations, result: Result<i32, String>, stdout: &str, registers: &[u32]) -> Vec<String> {
+    let mut problems = vec![];
+    match (&expectations.error, result) {
+        (Some(expected), Err(error)) if !error.contains(expected.as_str()) => {
+            problems.push(format!("error: expected {:?}, got {:?}", expected, error));
+        }
+        (Some(expected), Ok(exit_code)) => {
+            problems.push(format!("error: expected {:?}, the program exited with {}", expected, exit_code));
+        }
+        (None, Err(error)) => problems.push(format!("error: {}", error)),
+        (None, Ok(exit_code)) if exit_code != expectations.exit_code.unwrap_or(0) => {
+            problems.push(format!("exit code: expected {}, got {}", expectations.exit_code.unwrap_or(0), exit_code));
+        }
+        _ => {}
+    }
+    if let Some(expected) = &expectations.stdout {
+        if expected != stdout {
+            problems.push("stdout:".to_string());
+            problems.extend(diff(expected, stdout));
+        }
+    }
+    for &(register, expected) in &expectations.registers {
+        if registers[register] != expected {
+            problems.push(format!("r{}: expected {}, got {}", register, expected as i32, registers[register] as i32));
+        }
+    }
+    problems
+}
+
+//the lines that differ, numbered from 1, with - for what was expected and + for what was printed
+fn diff(expected: &str, actual: &str) -> Vec<String> {
+    let expected: Vec<&str> = expected.split_inclusive('\n').collect();
+    let actual: Vec<&str> = actual.split_inclusive('\n').collect();
+    let mut lines = vec![];
+    for number in 0..expected.len().max(actual.len()) {
+        let (old, new) = (expected.get(number), actual.get(number));
+        if old == new {
+            continue;
+        }
+        lines.push(format!("  line {}:", number + 1));
+        if let Some(old) = old {
+            lines.push(format!("  - {:?}", old));
+        }
+        if let Some(new) = new {
+            lines.push(format!("  + {:?}", new));
+        }
+    }
+    lines
+}
+
+//\n, \t, \0, \\ and a \ at the end of the text, so trailing spaces can be written
+fn unescape(text: &str) -> Result<String, Box<dyn Error>> {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('0') => unescaped.push('\0'),
+            Some('\\') => unescaped.push('\\'),
+            None => {}
+            Some(c) => return Err(format!("unknown escape \\{}", c).into()),
+        }
+    }
+    Ok(unescaped)
+}
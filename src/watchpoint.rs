@@ -0,0 +1,194 @@
+use crate::carpet::cvm::{CVM, REGISTER_COUNT, STACK_SIZE};
+use crate::carpet::cvm_heap::HEAP_SIZE;
+use crate::carpet::instructions::Register;
+use crate::carpet::memory::{HEAP_BASE, STACK_BASE, WORD_BYTES};
+use crate::carpet::tracer::Change;
+use crate::carpet::undo::UndoLog;
+use crate::parser::number::{parse_unsigned, parse_word};
+use std::error::Error;
+use std::fmt;
+
+//a register, or the word at an address
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    Register(Register),
+    Memory(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Condition {
+    //an instruction gave it another value
+    Changes,
+    //an instruction wrote it, also with the value it had
+    Written,
+    //an instruction wrote this value to it
+    Equals(u32),
+}
+
+//Stops the debugger after an instruction that writes a register, a stack slot or
+//a word of memory. What an instruction wrote comes from the changes the CVM
+//reports to the undo log from its write paths.
+#[derive(Debug, Clone)]
+pub struct Watchpoint {
+    target: Target,
+    condition: Condition,
+    //the target as it was typed
+    name: String,
+}
+
+impl Watchpoint {
+    //what [written | == value], where what is r5, stack[12], heap[300] for the word
+    //at that offset into the heap, or an address like 0x10130
+    pub fn parse(arguments: &[&str]) -> Result<Self, Box<dyn Error>> {
+        let (name, condition) = match arguments {
+            [name] => (*name, Condition::Changes),
+            [name, "written"] => (*name, Condition::Written),
+            [name, "==", value] => (*name, Condition::Equals(parse_word(value).ok_or_else(|| format!("invalid value {}", value))?)),
+            _ => return Err("watch needs a register, stack[n], heap[n] or an address, then written or == value".into()),
+        };
+        Ok(Self { target: target(name)?, condition, name: name.to_string() })
+    }
+
+    //the value of the target now, None for memory that isn't mapped
+    pub fn value(&self, cvm: &mut CVM<UndoLog>) -> Option<u32> {
+        match self.target {
+            Target::Register(register) => Some(cvm.registers()[register as usize]),
+            Target::Memory(address) => {
+                let bytes = cvm.read_memory(address, WORD_BYTES).ok()?;
+                Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            }
+        }
+    }
+
+    //whether an instruction that made changes, turning the target from before into after, stops here
+    pub fn fires(&self, changes: &[Change], before: Option<u32>, after: Option<u32>) -> bool {
+        let written = changes.iter().any(|change| match (*change, self.target) {
+            (Change::Register(register, _), Target::Register(watched)) => register == watched,
+            (Change::Memory(address, len, _), Target::Memory(watched)) => {
+                address < watched + WORD_BYTES && watched < address + len
+            }
+            _ => false,
+        });
+        match self.condition {
+            Condition::Changes => written && before != after,
+            Condition::Written => written,
+            Condition::Equals(value) => written && after == Some(value),
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.condition {
+            Condition::Changes => write!(f, "{} changes", self.name),
+            Condition::Written => write!(f, "{} is written", self.name),
+            Condition::Equals(value) => write!(f, "{} == {}", self.name, value as i32),
+        }
+    }
+}
+
+fn target(name: &str) -> Result<Target, Box<dyn Error>> {
+    let invalid = || format!("invalid watch target {}", name);
+    if let Some(register) = name.strip_prefix('r') {
+        return match register.parse::<usize>() {
+            Ok(register) if register < REGISTER_COUNT => Ok(Target::Register(register as Register)),
+            _ => Err(invalid().into()),
+        };
+    }
+    let index = |prefix: &str| {
+        name.strip_prefix(prefix)
+            .and_then(|rest| rest.strip_prefix('['))
+            .and_then(|rest| rest.strip_suffix(']'))
+            .map(|index| parse_unsigned::<usize>(index).ok_or_else(invalid))
+    };
+    if let Some(slot) = index("stack") {
+        let slot = slot?;
+        if slot >= STACK_SIZE {
+            return Err(format!("the stack has {} slots", STACK_SIZE).into());
+        }
+        return Ok(Target::Memory(STACK_BASE + slot * WORD_BYTES));
+    }
+    if let Some(offset) = index("heap") {
+        let offset = offset?;
+        if offset + WORD_BYTES > HEAP_SIZE {
+            return Err(format!("the heap has {} bytes", HEAP_SIZE).into());
+        }
+        return Ok(Target::Memory(HEAP_BASE + offset));
+    }
+    match name.strip_prefix("0x") {
+        Some(_) => Ok(Target::Memory(parse_unsigned(name).ok_or_else(invalid)?)),
+        None => Err(invalid().into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carpet_assembler::assembler::CarpetAssembler;
+    use crate::parser::parse::Parser;
+
+    const WRITES: &str = "\
+        loadi r1 5\n\
+        loadi r1 5\n\
+        loadi r1 6\n\
+        push r1\n\
+        loadi r2 0\n\
+        swrite r1 r2\n\
+        loadi r3 8\n\
+        malloc r3 r4\n\
+        write r1 r4\n\
+        store8 r3 r4\n\
+        inc r4\n\
+        store8 r3 r4\n";
+
+    //the lines of WRITES whose instruction sets off the watchpoint
+    fn fired(arguments: &[&str]) -> Vec<usize> {
+        let watchpoint = Watchpoint::parse(arguments).unwrap();
+        let module = Parser::new().parse_source("writes.cbc", WRITES).unwrap();
+        let mut cvm = CVM::with_tracer(UndoLog::new());
+        cvm.new_program(CarpetAssembler::new().assemble(module).unwrap());
+        let mut lines = vec![];
+        while let Some((pc, _)) = cvm.next_instruction() {
+            let before = watchpoint.value(&mut cvm);
+            cvm.step().unwrap();
+            let after = watchpoint.value(&mut cvm);
+            if watchpoint.fires(&cvm.tracer_mut().last(), before, after) {
+                lines.push(cvm.debug_info().lookup(pc).unwrap().entry.line);
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn registers() {
+        assert_eq!(fired(&["r1"]), vec![1, 3]);
+        assert_eq!(fired(&["r1", "written"]), vec![1, 2, 3]);
+        assert_eq!(fired(&["r1", "==", "6"]), vec![3]);
+        assert_eq!(fired(&["r1", "==", "-6"]), Vec::<usize>::new());
+        assert_eq!(fired(&["r9"]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn stack_slots() {
+        assert_eq!(fired(&["stack[0]"]), vec![4]);
+        assert_eq!(fired(&["stack[0]", "written"]), vec![4, 6]);
+        assert_eq!(fired(&["stack[0]", "==", "0x6"]), vec![4, 6]);
+        assert_eq!(fired(&["stack[1]", "written"]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn heap_words() {
+        //malloc doesn't write the heap, the byte stores write part of the word
+        assert_eq!(fired(&["heap[0]"]), vec![9, 10, 12]);
+        assert_eq!(fired(&["heap[0]", "==", "8"]), vec![10]);
+        assert_eq!(fired(&["0x10000", "written"]), vec![9, 10, 12]);
+        assert_eq!(fired(&["heap[4]", "written"]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn invalid_targets() {
+        for arguments in [&["r32"][..], &["stack[256]"], &["heap[-1]"], &["heap[0x3fffd]"], &["65536"], &["r1", "==", "x"], &["r1", "is"]] {
+            assert!(Watchpoint::parse(arguments).is_err(), "{:?}", arguments);
+        }
+    }
+}